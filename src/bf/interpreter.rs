@@ -1,132 +1,467 @@
+use crate::bf::ops::{Op, Program};
 use std::io::{Read, Write};
+use std::str::FromStr;
+use thiserror::Error;
 
-const MAX_INSTRUCTIONS: usize = 100_000_000_000_000;
+pub const MAX_INSTRUCTIONS: usize = 100_000_000_000_000;
+
+/// How many temporary cells left of cell zero are checked by `#`
+pub const CHECKED_TEMPS: usize = 20;
+
+/// Cell that sanitizer checks of generated code set to stop the program at the next `#`
+///
+/// It is the last cell `#` checks, which no temporary variable uses.
+pub const TRAP_CELL: isize = -(CHECKED_TEMPS as isize);
+
+/// Everything that can stop a brainfuck program before it reaches its end
+///
+/// Positions are byte offsets into the brainfuck source.
+#[derive(Debug, Error)]
+pub enum RuntimeError {
+    #[error("Unbalanced bracket at position {position}")]
+    UnbalancedBracket { position: usize },
+
+    #[error("Step limit of {limit} exceeded at position {position}")]
+    StepLimitExceeded { limit: usize, position: usize },
+
+    #[error("I/O error at position {position}: {source}")]
+    Io {
+        position: usize,
+        source: std::io::Error,
+    },
+
+    #[error("Cell {cell} overflowed to {value} at position {position}")]
+    CellOverflow {
+        cell: isize,
+        value: i64,
+        position: usize,
+    },
+
+    #[error("Pointer moved out of bounds to cell {cell} at position {position}")]
+    PointerOutOfBounds { cell: isize, position: usize },
+
+    #[error("Invariant violated at position {position}: {message}")]
+    InvariantViolation { position: usize, message: String },
+
+    #[error("Array index out of range at position {position}")]
+    IndexOutOfRange { position: usize },
+
+    /// Found before anything runs, at position 0
+    #[error("Tape length must not be zero")]
+    EmptyTape,
+}
+
+impl RuntimeError {
+    /// Position in the brainfuck source where the error happened
+    pub fn position(&self) -> usize {
+        match self {
+            RuntimeError::UnbalancedBracket { position }
+            | RuntimeError::StepLimitExceeded { position, .. }
+            | RuntimeError::Io { position, .. }
+            | RuntimeError::CellOverflow { position, .. }
+            | RuntimeError::PointerOutOfBounds { position, .. }
+            | RuntimeError::InvariantViolation { position, .. }
+            | RuntimeError::IndexOutOfRange { position } => *position,
+            RuntimeError::EmptyTape => 0,
+        }
+    }
+}
+
+/// Statistics of a finished run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionReport {
+    /// Number of ops executed
+    pub steps: usize,
+    /// Position in the source and number of executions of every op, if profiling was enabled
+    pub profile: Option<Vec<(usize, usize)>>,
+}
+
+/// Size of a single tape cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    /// Largest value a cell can hold
+    pub fn max(self) -> u32 {
+        match self {
+            CellWidth::U8 => u8::MAX as u32,
+            CellWidth::U16 => u16::MAX as u32,
+            CellWidth::U32 => u32::MAX,
+        }
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellWidth::U8),
+            "16" => Ok(CellWidth::U16),
+            "32" => Ok(CellWidth::U32),
+            _ => Err(format!("Invalid cell width `{s}`, expected 8, 16 or 32")),
+        }
+    }
+}
+
+/// What happens when `+` or `-` goes past the range of a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    Wrap,
+    Error,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(OverflowPolicy::Wrap),
+            "error" => Ok(OverflowPolicy::Error),
+            _ => Err(format!(
+                "Invalid overflow policy `{s}`, expected wrap or error"
+            )),
+        }
+    }
+}
+
+/// What `,` stores into the cell when there is no more input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// Store 0
+    Zero,
+    /// Store the largest value of a cell, 255 or -1 for 8 bit cells
+    Max,
+    /// Leave the cell as is
+    Unchanged,
+}
+
+impl FromStr for EofPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" | "zero" => Ok(EofPolicy::Zero),
+            "-1" | "255" | "max" => Ok(EofPolicy::Max),
+            "unchanged" => Ok(EofPolicy::Unchanged),
+            _ => Err(format!(
+                "Invalid EOF policy `{s}`, expected zero, max or unchanged"
+            )),
+        }
+    }
+}
+
+/// What happens when the pointer moves off either end of the tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerPolicy {
+    /// Continue from the other end of the tape
+    Wrap,
+    Error,
+    /// Extend the tape in that direction
+    Grow,
+}
+
+impl FromStr for PointerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(PointerPolicy::Wrap),
+            "error" => Ok(PointerPolicy::Error),
+            "grow" => Ok(PointerPolicy::Grow),
+            _ => Err(format!(
+                "Invalid pointer policy `{s}`, expected wrap, error or grow"
+            )),
+        }
+    }
+}
+
+/// Machine model of the interpreter
+///
+/// The default matches the classic implementation: 30000 8-bit wrapping cells, a wrapping pointer
+/// and 0 on EOF. Generated code keeps its temporary variables left of cell zero, so it needs the
+/// `Wrap` or `Grow` pointer policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterpreterConfig {
+    /// Initial length of the tape, loading a program fails if it is zero
+    pub tape_length: usize,
+    pub cell_width: CellWidth,
+    pub overflow: OverflowPolicy,
+    pub eof: EofPolicy,
+    pub pointer: PointerPolicy,
+    /// Maximum number of ops to execute before giving up
+    pub step_limit: usize,
+}
+
+impl Default for InterpreterConfig {
+    fn default() -> Self {
+        Self {
+            tape_length: 30000,
+            cell_width: CellWidth::U8,
+            overflow: OverflowPolicy::Wrap,
+            eof: EofPolicy::Zero,
+            pointer: PointerPolicy::Wrap,
+            step_limit: MAX_INSTRUCTIONS,
+        }
+    }
+}
 
 pub struct Interpreter {
-    tape: [u8; 30000],
-    pointer: i32,
+    config: InterpreterConfig,
+    tape: Vec<u32>,
+    /// Index in `tape` of cell zero, only changes when the tape grows to the left
+    origin: usize,
+    pointer: usize,
     instructions_ran: usize,
+    program: Program,
+    instruction_index: usize,
+    /// Number of executions of every op of the program
+    op_counts: Option<Vec<usize>>,
 }
 
-// This interpreter is slow as fuck but will do
 impl Interpreter {
+    #[allow(dead_code)]
     pub fn new() -> Self {
+        Self::with_config(InterpreterConfig::default())
+    }
+
+    pub fn with_config(config: InterpreterConfig) -> Self {
         Interpreter {
-            tape: [0; 30000],
+            config,
+            tape: vec![0; config.tape_length],
+            origin: 0,
             pointer: 0,
             instructions_ran: 0,
+            program: Program::default(),
+            instruction_index: 0,
+            op_counts: None,
         }
     }
 
-    pub fn run(mut self, code: &str, input: &mut impl Read, output: &mut impl Write) {
-        let jump_table = Self::calculate_jumps(code);
-        let mut instruction_index = 0;
-        let code: Vec<char> = code.chars().collect();
+    /// Count how many times every op is executed, see [`ExecutionReport::profile`]
+    pub fn with_profiling(mut self) -> Self {
+        self.op_counts = Some(Vec::new());
+        self
+    }
 
-        assert_eq!(
-            code.iter().filter(|x| **x == '[').count(),
-            code.iter().filter(|x| **x == ']').count(),
-            "Uneven number of [ and ]"
-        );
+    pub fn run(
+        mut self,
+        code: &str,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<ExecutionReport, RuntimeError> {
+        self.load(code)?;
+        while self.step(input, output)? {}
 
-        // TODO Parse BF into instructions that can execute faster `+++` -> Add(3)
+        let profile = self
+            .op_counts
+            .map(|counts| self.program.positions.iter().copied().zip(counts).collect());
 
-        while instruction_index < code.len() {
-            self.instructions_ran += 1;
-            if self.instructions_ran == MAX_INSTRUCTIONS {
-                panic!("Too many instructions");
-            }
+        Ok(ExecutionReport {
+            steps: self.instructions_ran,
+            profile,
+        })
+    }
 
-            match code[instruction_index] {
-                '>' => self.pointer_right(),
-                '<' => self.pointer_left(),
-                '+' => {
-                    self.tape[self.pointer as usize] =
-                        self.tape[self.pointer as usize].wrapping_add(1)
-                }
-                '-' => {
-                    self.tape[self.pointer as usize] =
-                        self.tape[self.pointer as usize].wrapping_sub(1)
-                }
-                '.' => {
-                    output
-                        .write_all(&[self.tape[self.pointer as usize]])
-                        .unwrap();
+    /// Compile `code` and prepare to execute it from the start with [`Interpreter::step`]
+    pub fn load(&mut self, code: &str) -> Result<(), RuntimeError> {
+        if self.tape.is_empty() {
+            return Err(RuntimeError::EmptyTape);
+        }
+        self.program = Program::compile(code)?;
+        self.instruction_index = 0;
+        if let Some(counts) = &mut self.op_counts {
+            *counts = vec![0; self.program.ops.len()];
+        }
+        Ok(())
+    }
+
+    /// Execute a single op, returns false once the program has finished
+    pub fn step(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<bool, RuntimeError> {
+        let Some(&op) = self.program.ops.get(self.instruction_index) else {
+            return Ok(false);
+        };
+        let position = self.program.positions[self.instruction_index];
+
+        if let Some(counts) = &mut self.op_counts {
+            counts[self.instruction_index] += 1;
+        }
+
+        self.instructions_ran += 1;
+        if self.instructions_ran > self.config.step_limit {
+            return Err(RuntimeError::StepLimitExceeded {
+                limit: self.config.step_limit,
+                position,
+            });
+        }
+
+        match op {
+            Op::Add(n) => self.add(self.pointer, n as i64, position)?,
+            Op::Move(n) => self.pointer = self.offset_index(n, position)?,
+            Op::Clear => self.tape[self.pointer] = 0,
+            // The loop never runs, so its targets are never reached
+            Op::MulAdd { .. } if self.tape[self.pointer] == 0 => {}
+            Op::MulAdd { offset, factor } => {
+                let value = self.tape[self.pointer] as i64;
+                let target = self.offset_index(offset, position)?;
+                self.add(target, value * factor as i64, position)?;
+            }
+            Op::ScanLeft(step) => {
+                while self.tape[self.pointer] != 0 {
+                    self.pointer = self.offset_index(-(step as isize), position)?;
                 }
-                ',' => {
-                    let mut buf = [0; 1];
-                    let read = input.read(&mut buf);
-
-                    match read {
-                        Ok(0) => {
-                            self.tape[self.pointer as usize] = 0;
-                        }
-                        Ok(n) => {
-                            if n == 1 {
-                                self.tape[self.pointer as usize] = buf[0];
-                            } else {
-                                panic!("Expected 1 byte, got {n}");
-                            }
-                        }
-                        Err(_) => panic!("Error reading input"),
-                    }
+            }
+            Op::ScanRight(step) => {
+                while self.tape[self.pointer] != 0 {
+                    self.pointer = self.offset_index(step as isize, position)?;
                 }
-                '[' => {
-                    if self.tape[self.pointer as usize] == 0 {
-                        instruction_index = jump_table[instruction_index];
-                    }
+            }
+            Op::Output => {
+                output
+                    .write_all(&[self.tape[self.pointer] as u8])
+                    .map_err(|source| RuntimeError::Io { position, source })?;
+            }
+            Op::Input => {
+                let mut buf = [0; 1];
+                let read = input
+                    .read(&mut buf)
+                    .map_err(|source| RuntimeError::Io { position, source })?;
+
+                match read {
+                    0 => match self.config.eof {
+                        EofPolicy::Zero => self.tape[self.pointer] = 0,
+                        EofPolicy::Max => self.tape[self.pointer] = self.config.cell_width.max(),
+                        EofPolicy::Unchanged => {}
+                    },
+                    _ => self.tape[self.pointer] = buf[0] as u32,
                 }
-                ']' => {
-                    if self.tape[self.pointer as usize] != 0 {
-                        instruction_index = jump_table[instruction_index];
-                    }
+            }
+            Op::JumpIfZero(target) if self.tape[self.pointer] == 0 => {
+                self.instruction_index = target
+            }
+            Op::JumpIfNonZero(target) if self.tape[self.pointer] != 0 => {
+                self.instruction_index = target
+            }
+            Op::JumpIfZero(_) | Op::JumpIfNonZero(_) => {}
+            Op::CheckTemps => {
+                if self.cell(TRAP_CELL) != 0 {
+                    return Err(RuntimeError::IndexOutOfRange { position });
                 }
-                '#' => {
-                    // Check all temporary variables are zero
-                    let temps = self.tape.iter().rev().take(20).collect::<Vec<&u8>>();
-                    assert!(
-                        temps.iter().all(|&x| *x == 0),
-                        "Temporary variables are not zero at instruction {instruction_index}: {temps:?}"
-                    );
+
+                // Check all temporary variables are zero
+                let temps = self.temps();
+                if temps.iter().any(|&x| x != 0) {
+                    return Err(RuntimeError::InvariantViolation {
+                        position,
+                        message: format!("Temporary variables are not zero: {temps:?}"),
+                    });
                 }
-                _ => {}
             }
+        }
+
+        self.instruction_index += 1;
+        Ok(true)
+    }
+
+    /// The loaded program
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// Index into the loaded program of the op that executes next
+    pub fn instruction_index(&self) -> usize {
+        self.instruction_index
+    }
+
+    /// Number of ops executed so far
+    pub fn steps(&self) -> usize {
+        self.instructions_ran
+    }
 
-            instruction_index += 1;
+    /// Cell the pointer is at, relative to cell zero
+    pub fn pointer(&self) -> isize {
+        self.pointer as isize - self.origin as isize
+    }
+
+    /// Value of a cell relative to cell zero
+    ///
+    /// Cells the tape has not grown to yet are zero.
+    pub fn cell(&self, cell: isize) -> u32 {
+        let index = self.origin as isize + cell;
+        match self.config.pointer {
+            PointerPolicy::Wrap => self.tape[index.rem_euclid(self.tape.len() as isize) as usize],
+            _ => usize::try_from(index)
+                .ok()
+                .and_then(|i| self.tape.get(i))
+                .copied()
+                .unwrap_or(0),
         }
     }
 
-    fn calculate_jumps(code: &str) -> Vec<usize> {
-        let mut jump_table = vec![0; code.len()];
-        let mut stack = Vec::new();
+    /// Add `n` to the cell at `index` according to the overflow policy
+    fn add(&mut self, index: usize, n: i64, position: usize) -> Result<(), RuntimeError> {
+        let max = self.config.cell_width.max() as i64;
+        let value = self.tape[index] as i64 + n;
 
-        for (i, c) in code.chars().enumerate() {
-            match c {
-                '[' => stack.push(i),
-                ']' => {
-                    if let Some(start) = stack.pop() {
-                        jump_table[start] = i;
-                        jump_table[i] = start;
-                    }
-                }
-                _ => {}
+        self.tape[index] = match self.config.overflow {
+            OverflowPolicy::Wrap => value.rem_euclid(max + 1) as u32,
+            OverflowPolicy::Error if !(0..=max).contains(&value) => {
+                return Err(RuntimeError::CellOverflow {
+                    cell: index as isize - self.origin as isize,
+                    value,
+                    position,
+                })
             }
-        }
+            OverflowPolicy::Error => value as u32,
+        };
 
-        jump_table
+        Ok(())
     }
 
-    fn pointer_left(&mut self) {
-        self.pointer -= 1;
-        if self.pointer < 0 {
-            self.pointer += self.tape.len() as i32;
+    /// Index of the cell `offset` away from the pointer according to the pointer policy
+    fn offset_index(&mut self, offset: isize, position: usize) -> Result<usize, RuntimeError> {
+        let len = self.tape.len() as isize;
+        let index = self.pointer as isize + offset;
+        if (0..len).contains(&index) {
+            return Ok(index as usize);
         }
+
+        Ok(match self.config.pointer {
+            PointerPolicy::Wrap => index.rem_euclid(len) as usize,
+            PointerPolicy::Error => {
+                return Err(RuntimeError::PointerOutOfBounds {
+                    cell: index - self.origin as isize,
+                    position,
+                })
+            }
+            PointerPolicy::Grow if index < 0 => {
+                // Grow at least by the current length to not reallocate on every move
+                let extra = index.unsigned_abs().max(self.tape.len());
+                self.tape.splice(0..0, std::iter::repeat_n(0, extra));
+                self.origin += extra;
+                self.pointer += extra;
+                (index + extra as isize) as usize
+            }
+            PointerPolicy::Grow => {
+                let new_len = (index as usize + 1).max(self.tape.len() * 2);
+                self.tape.resize(new_len, 0);
+                index as usize
+            }
+        })
     }
 
-    fn pointer_right(&mut self) {
-        self.pointer += 1;
-        if self.pointer >= self.tape.len() as i32 {
-            self.pointer -= self.tape.len() as i32;
-        }
+    /// Values of the temporary cells, starting from the one closest to cell zero
+    fn temps(&self) -> Vec<u32> {
+        (1..=CHECKED_TEMPS as isize)
+            .map(|i| self.cell(-i))
+            .collect()
     }
 }
@@ -1,5 +1,10 @@
+use crate::pipeline::OptLevel;
+use std::ops::Range;
+
 pub mod interpreter;
+pub mod ops;
 pub mod optim;
+mod tests;
 
 pub fn add_header(s: String) -> String {
     let header = format!(
@@ -9,10 +14,72 @@ pub fn add_header(s: String) -> String {
     format!("{header}\n{s}")
 }
 
+/// Optimize at the most aggressive level
+#[allow(dead_code)]
 pub fn optimize(bf: String) -> String {
-    let bf = optim::optimize_no_effect(bf);
-    let bf = optim::remove_redundant_zeros(bf);
-    let bf = optim::optimize_no_effect(bf);
-    let bf = optim::remove_non_brainfuck(bf);
-    add_header(bf)
+    optimize_with_map(bf, &[], OptLevel::O3, false).0
+}
+
+/// Optimize at `level`, also moving `ranges` of the input to where their code ended up in the output
+///
+/// `O0` returns the code and ranges as they are.
+///
+/// Every range must start at the beginning or right after an instruction separator (`#`) and end
+/// at the end or right before one. No pass removes or reorders separators, so the ranges are found
+/// again by counting them. With `keep_separators`, they are also left in the output, so the
+/// interpreter still checks the temporary cells and sanitizer traps at them. Separators from
+/// removed loops are only counted, as the temporary cells need not be zero where they end up.
+pub fn optimize_with_map(
+    bf: String,
+    ranges: &[Range<usize>],
+    level: OptLevel,
+    keep_separators: bool,
+) -> (String, Vec<Range<usize>>) {
+    if level == OptLevel::O0 {
+        return (bf, ranges.to_vec());
+    }
+
+    let separators_before = |code: &str, i: usize| code[..i].matches('#').count();
+    let anchors: Vec<(usize, usize)> = ranges
+        .iter()
+        .map(|r| {
+            (
+                separators_before(&bf, r.start),
+                separators_before(&bf, r.end),
+            )
+        })
+        .collect();
+
+    let bf = optim::optimize_tree(bf, level);
+
+    let separators: Vec<usize> = bf
+        .match_indices(['#', optim::DEAD_SEPARATOR])
+        .map(|(i, _)| i)
+        .collect();
+
+    // Number of characters left before each position once non-brainfuck is removed
+    let is_kept = |c: char| "+-><[].,".contains(c) || (keep_separators && c == '#');
+    let mut kept = vec![0];
+    for c in bf.chars() {
+        kept.push(kept.last().unwrap() + is_kept(c) as usize);
+    }
+
+    let ranges = anchors
+        .into_iter()
+        .map(|(start, end)| {
+            let start = if start == 0 {
+                0
+            } else {
+                separators[start - 1] + 1
+            };
+            let end = separators.get(end).copied().unwrap_or(bf.len());
+            kept[start]..kept[end]
+        })
+        .collect();
+
+    let bf = match keep_separators {
+        true => bf.chars().filter(|&c| is_kept(c)).collect(),
+        false => optim::remove_non_brainfuck(bf),
+    };
+    (bf, ranges)
 }
@@ -0,0 +1,194 @@
+use crate::bf::interpreter::RuntimeError;
+use std::collections::BTreeMap;
+
+/// A single brainfuck operation after lowering
+///
+/// Runs of the same character are merged, and common loop idioms are replaced by a single
+/// operation that has the same effect on the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Add to the current cell, negative for `-`
+    Add(i32),
+
+    /// Move the pointer, negative for `<`
+    Move(isize),
+
+    /// Set the current cell to zero, `[-]`
+    Clear,
+
+    /// Add the current cell multiplied by `factor` to the cell at `offset`
+    ///
+    /// Always followed by a [`Op::Clear`], together they replace loops like `[->++>+<<]`
+    MulAdd { offset: isize, factor: i32 },
+
+    /// Move left by `step` until the current cell is zero, `[<]`
+    ScanLeft(usize),
+
+    /// Move right by `step` until the current cell is zero, `[>]`
+    ScanRight(usize),
+
+    /// Read one byte from input, `,`
+    Input,
+
+    /// Write the current cell to output, `.`
+    Output,
+
+    /// Jump past the matching [`Op::JumpIfNonZero`] if the current cell is zero, `[`
+    JumpIfZero(usize),
+
+    /// Jump back past the matching [`Op::JumpIfZero`] if the current cell is not zero, `]`
+    JumpIfNonZero(usize),
+
+    /// Check that all temporary variables are zero, `#`
+    CheckTemps,
+}
+
+/// Brainfuck lowered into [`Op`]s
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    /// Byte offset in the original source of the character each op starts at
+    pub positions: Vec<usize>,
+}
+
+impl Program {
+    /// Lower brainfuck source into ops
+    ///
+    /// Any character that is not a brainfuck command or `#` is ignored.
+    pub fn compile(code: &str) -> Result<Self, RuntimeError> {
+        let bytes = code.as_bytes();
+        let mut program = Program::default();
+        let mut loop_starts = Vec::new();
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let start = i;
+            match bytes[i] {
+                b'+' | b'-' => {
+                    let run = Self::run_length(bytes, i);
+                    let n = run as i32;
+                    program.push(
+                        if bytes[i] == b'+' {
+                            Op::Add(n)
+                        } else {
+                            Op::Add(-n)
+                        },
+                        start,
+                    );
+                    i += run;
+                    continue;
+                }
+                b'>' | b'<' => {
+                    let run = Self::run_length(bytes, i);
+                    let n = run as isize;
+                    program.push(
+                        if bytes[i] == b'>' {
+                            Op::Move(n)
+                        } else {
+                            Op::Move(-n)
+                        },
+                        start,
+                    );
+                    i += run;
+                    continue;
+                }
+                b'.' => program.push(Op::Output, start),
+                b',' => program.push(Op::Input, start),
+                b'#' => program.push(Op::CheckTemps, start),
+                b'[' => {
+                    if let Some((ops, len)) = Self::loop_idiom(&bytes[i..]) {
+                        for op in ops {
+                            program.push(op, start);
+                        }
+                        i += len;
+                        continue;
+                    }
+                    loop_starts.push(program.ops.len());
+                    program.push(Op::JumpIfZero(0), start);
+                }
+                b']' => {
+                    let open = loop_starts
+                        .pop()
+                        .ok_or(RuntimeError::UnbalancedBracket { position: start })?;
+                    let close = program.ops.len();
+                    program.ops[open] = Op::JumpIfZero(close);
+                    program.push(Op::JumpIfNonZero(open), start);
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+
+        if let Some(&open) = loop_starts.last() {
+            return Err(RuntimeError::UnbalancedBracket {
+                position: program.positions[open],
+            });
+        }
+
+        Ok(program)
+    }
+
+    fn push(&mut self, op: Op, position: usize) {
+        self.ops.push(op);
+        self.positions.push(position);
+    }
+
+    /// Count how many times the character at `start` repeats
+    fn run_length(bytes: &[u8], start: usize) -> usize {
+        bytes[start..]
+            .iter()
+            .take_while(|&&c| c == bytes[start])
+            .count()
+    }
+
+    /// Recognize a loop without nested loops or I/O that can be replaced by simpler ops
+    ///
+    /// `code` must start with `[`. Returns the replacement and the length of the loop in bytes.
+    fn loop_idiom(code: &[u8]) -> Option<(Vec<Op>, usize)> {
+        let mut deltas: BTreeMap<isize, i32> = BTreeMap::new();
+        let mut offset = 0isize;
+
+        for (i, c) in code.iter().enumerate().skip(1) {
+            match c {
+                b'+' => *deltas.entry(offset).or_default() += 1,
+                b'-' => *deltas.entry(offset).or_default() -= 1,
+                b'>' => offset += 1,
+                b'<' => offset -= 1,
+                b']' => {
+                    deltas.retain(|_, d| *d != 0);
+                    let len = i + 1;
+
+                    // `[>>]` and `[<]`
+                    if deltas.is_empty() {
+                        return match offset {
+                            0 => None,
+                            o if o > 0 => Some((vec![Op::ScanRight(o as usize)], len)),
+                            o => Some((vec![Op::ScanLeft(o.unsigned_abs())], len)),
+                        };
+                    }
+
+                    if offset != 0 {
+                        return None;
+                    }
+
+                    // The loop cell must count down by one for the iteration count to be its
+                    // value. Counting up would depend on the cell width and overflow policy.
+                    if deltas.remove(&0) != Some(-1) {
+                        return None;
+                    }
+
+                    let mut ops: Vec<Op> = deltas
+                        .into_iter()
+                        .map(|(offset, factor)| Op::MulAdd { offset, factor })
+                        .collect();
+                    ops.push(Op::Clear);
+                    return Some((ops, len));
+                }
+                b'[' | b'.' | b',' | b'#' => return None,
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
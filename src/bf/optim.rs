@@ -1,96 +1,483 @@
-use std::collections::HashSet;
-use std::fmt::format;
+use crate::bf::interpreter::CHECKED_TEMPS;
+use crate::pipeline::OptLevel;
+use std::collections::{BTreeMap, BTreeSet};
 
-/// Optimizes series of instructions that have no effect, e.g. <><> or +-+-
-pub fn optimize_no_effect(bf: String) -> String {
-    // Literally remove <> and >< and +- and -+ until nothing changes
+/// Emitted for a [`Node::DeadSeparator`], so it still counts as a separator for source maps but
+/// is not run as a check of the temporary cells
+pub const DEAD_SEPARATOR: char = '$';
 
-    let mut new = bf.clone();
+/// Brainfuck parsed into a tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// Run of `+` or `-`
+    Add(i32),
+    /// Run of `>` or `<`
+    Move(isize),
+    Input,
+    Output,
+    /// Instruction separator `#`, never removed or reordered so source maps stay valid
+    Separator,
+    /// Separator left over from a loop that never runs, so the temporary cells aren't known to
+    /// be zero there
+    DeadSeparator,
+    Loop(Vec<Node>),
+    /// `[-]`
+    Clear,
+    /// Loop like `[->++<]`, adds the current cell times `factor` to the cell at every offset and
+    /// clears the current cell
+    MulLoop(Vec<(isize, i32)>),
+}
+
+/// Values of cells known at some point of the program
+///
+/// Cells are addressed relative to where the pointer was at the start. Values are tracked
+/// without wrapping, which is still correct when cells do wrap, as only differences are emitted.
+#[derive(Debug, Clone)]
+struct Tape {
+    /// Known values, `None` marks a cell that would otherwise fall under `rest_zero`
+    cells: BTreeMap<isize, Option<i64>>,
+    /// Cells not in `cells` are zero, true until the pointer is lost
+    rest_zero: bool,
+    /// The pointer is known relative to the start
+    absolute: bool,
+    position: isize,
+}
+
+impl Tape {
+    fn new() -> Self {
+        Self {
+            cells: BTreeMap::new(),
+            rest_zero: true,
+            absolute: true,
+            position: 0,
+        }
+    }
+
+    fn get(&self, cell: isize) -> Option<i64> {
+        match self.cells.get(&cell) {
+            Some(&value) => value,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, cell: isize, value: Option<i64>) {
+        self.cells.insert(cell, value);
+    }
+
+    fn current(&self) -> Option<i64> {
+        self.get(self.position)
+    }
+
+    fn set_current(&mut self, value: Option<i64>) {
+        self.set(self.position, value);
+    }
+
+    /// Forget everything, after the pointer moved by an unknown amount
+    fn forget(&mut self) {
+        self.cells.clear();
+        self.rest_zero = false;
+        self.absolute = false;
+    }
+}
+
+/// Parse brainfuck into a tree, merging runs of the same character
+///
+/// Everything but brainfuck commands and `#` is dropped. Returns `None` for unbalanced brackets.
+pub fn parse(bf: &str) -> Option<Vec<Node>> {
+    let mut stack = vec![Vec::new()];
+
+    for c in bf.chars() {
+        let nodes = stack.last_mut().unwrap();
+        match c {
+            '+' => push_add(nodes, 1),
+            '-' => push_add(nodes, -1),
+            '>' => push_move(nodes, 1),
+            '<' => push_move(nodes, -1),
+            '.' => nodes.push(Node::Output),
+            ',' => nodes.push(Node::Input),
+            '#' => nodes.push(Node::Separator),
+            '[' => stack.push(Vec::new()),
+            ']' => {
+                let body = stack.pop().unwrap();
+                stack.last_mut()?.push(Node::Loop(body));
+            }
+            _ => {}
+        }
+    }
+
+    if stack.len() != 1 {
+        return None;
+    }
+    stack.pop()
+}
+
+fn push_add(nodes: &mut Vec<Node>, n: i32) {
+    match nodes.last_mut() {
+        Some(Node::Add(m)) => *m += n,
+        _ => nodes.push(Node::Add(n)),
+    }
+}
+
+fn push_move(nodes: &mut Vec<Node>, n: isize) {
+    match nodes.last_mut() {
+        Some(Node::Move(m)) => *m += n,
+        _ => nodes.push(Node::Move(n)),
+    }
+}
+
+/// Turn a tree back into brainfuck
+pub fn emit(nodes: &[Node]) -> String {
+    let mut out = String::new();
+    emit_into(nodes, &mut out);
+    out
+}
+
+fn emit_into(nodes: &[Node], out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Add(n) => emit_add(*n, out),
+            Node::Move(n) => emit_move(*n, out),
+            Node::Input => out.push(','),
+            Node::Output => out.push('.'),
+            Node::Separator => out.push('#'),
+            Node::DeadSeparator => out.push(DEAD_SEPARATOR),
+            Node::Loop(body) => {
+                out.push('[');
+                emit_into(body, out);
+                out.push(']');
+            }
+            Node::Clear => *out += "[-]",
+            Node::MulLoop(targets) => {
+                out.push('[');
+                out.push('-');
+                let mut position = 0;
+                for &(offset, factor) in targets {
+                    emit_move(offset - position, out);
+                    emit_add(factor, out);
+                    position = offset;
+                }
+                emit_move(-position, out);
+                out.push(']');
+            }
+        }
+    }
+}
+
+fn emit_add(n: i32, out: &mut String) {
+    let c = if n > 0 { "+" } else { "-" };
+    *out += &c.repeat(n.unsigned_abs() as usize);
+}
+
+fn emit_move(n: isize, out: &mut String) {
+    let c = if n > 0 { ">" } else { "<" };
+    *out += &c.repeat(n.unsigned_abs());
+}
+
+/// Optimize brainfuck by running the passes of `level` on its tree until nothing changes
+///
+/// Cells are assumed to wrap. Code with unbalanced brackets is returned as is.
+pub fn optimize_tree(bf: String, level: OptLevel) -> String {
+    let Some(mut nodes) = parse(&bf) else {
+        return bf;
+    };
 
     loop {
-        let old = new.clone();
-        new = new
-            .replace("><", "")
-            .replace("<>", "")
-            .replace("+-", "")
-            .replace("[-][-]", "[-]")
-            .replace("-+", "");
-        if old == new {
+        let old = nodes.clone();
+        nodes = cancel_moves(nodes);
+        nodes = recognize_loops(nodes);
+        if level.known_values() {
+            nodes = propagate_constants(nodes, &mut Tape::new(), level);
+        }
+        if old == nodes {
             break;
         }
     }
 
-    new
+    // Moving the pointer at the very end does nothing
+    while let Some(Node::Move(_)) = nodes.last() {
+        nodes.pop();
+    }
+
+    emit(&nodes)
 }
 
-/// Removes all non-brainfuck characters from the input
-pub fn remove_non_brainfuck(bf: String) -> String {
-    bf.chars()
-        .filter(|&c| "+-><[].,".contains(c))
-        .collect::<String>()
-        .trim()
-        .to_string()
+/// Merge the cell changes of straight-line code with their offsets, so that `+>-<+` becomes
+/// `++>-<` and `>+<<->` becomes `>+<<->`, but `>><<` disappears
+///
+/// Changes are not moved across separators, only the pointer movement between them is merged.
+fn cancel_moves(nodes: Vec<Node>) -> Vec<Node> {
+    let mut out = Vec::new();
+    // Cell changes in order of first use, for every part between separators
+    let mut segments: Vec<Vec<(isize, i32)>> = vec![Vec::new()];
+    // The separators between the segments
+    let mut separators = Vec::new();
+    let mut offset = 0;
+
+    for node in nodes {
+        match node {
+            Node::Add(n) => {
+                let segment = segments.last_mut().unwrap();
+                match segment.iter_mut().find(|(o, _)| *o == offset) {
+                    Some((_, d)) => *d += n,
+                    None => segment.push((offset, n)),
+                }
+            }
+            Node::Move(n) => offset += n,
+            Node::Separator | Node::DeadSeparator => {
+                segments.push(Vec::new());
+                separators.push(node);
+            }
+            node => {
+                flush_block(&mut out, &mut segments, &mut separators, &mut offset);
+                out.push(match node {
+                    Node::Loop(body) => Node::Loop(cancel_moves(body)),
+                    node => node,
+                });
+            }
+        }
+    }
+    flush_block(&mut out, &mut segments, &mut separators, &mut offset);
+
+    out
+}
+
+/// Emit the straight-line code collected by [`cancel_moves`]
+fn flush_block(
+    out: &mut Vec<Node>,
+    segments: &mut Vec<Vec<(isize, i32)>>,
+    separators: &mut Vec<Node>,
+    offset: &mut isize,
+) {
+    let mut position = 0;
+    let mut separators = separators.drain(..);
+
+    for segment in segments.drain(..) {
+        for (o, d) in segment {
+            if d == 0 {
+                continue;
+            }
+            if o != position {
+                out.push(Node::Move(o - position));
+            }
+            out.push(Node::Add(d));
+            position = o;
+        }
+        out.extend(separators.next());
+    }
+
+    if *offset != position {
+        out.push(Node::Move(*offset - position));
+    }
+
+    segments.push(Vec::new());
+    *offset = 0;
 }
 
-pub fn remove_redundant_zeros(bf: String) -> String {
-    // Removes all `[-]` that do not do anything
-    // At this stage we need `#`/instruction separators to be kept
-
-    let split = bf.split("[-]");
-    let mut output_code = String::new();
-    let mut pointer = 0;
-    let mut bracket_index = 0;
-    let mut used_variables = vec![];
-
-    let mut debug_index = 0;
-
-    for code in split {
-        debug_index += 1;
-        for char in code.chars() {
-            match char {
-                '<' => pointer -= 1,
-                '>' => pointer += 1,
-                '[' => {
-                    bracket_index += 1;
-                    if !used_variables.contains(&pointer) {
-                        used_variables.push(pointer)
+/// Replace clear loops `[-]` and multiplication loops `[->+++<]` with their own nodes
+fn recognize_loops(nodes: Vec<Node>) -> Vec<Node> {
+    nodes
+        .into_iter()
+        .map(|node| match node {
+            Node::Loop(body) => {
+                let body = recognize_loops(body);
+                simple_loop(&body).unwrap_or(Node::Loop(body))
+            }
+            node => node,
+        })
+        .collect()
+}
+
+fn simple_loop(body: &[Node]) -> Option<Node> {
+    let mut deltas = BTreeMap::new();
+    let mut offset = 0;
+
+    for node in body {
+        match node {
+            Node::Add(n) => *deltas.entry(offset).or_insert(0) += n,
+            Node::Move(n) => offset += n,
+            _ => return None,
+        }
+    }
+
+    if offset != 0 {
+        return None;
+    }
+
+    // `[+]` clears as well, as cells wrap
+    match deltas.remove(&0) {
+        Some(-1) => {}
+        Some(1) if deltas.is_empty() => {}
+        _ => return None,
+    }
+
+    let targets: Vec<(isize, i32)> = deltas.into_iter().filter(|(_, d)| *d != 0).collect();
+    Some(if targets.is_empty() {
+        Node::Clear
+    } else {
+        Node::MulLoop(targets)
+    })
+}
+
+/// Track known cell values to remove clears and loops that do nothing
+///
+/// A `[-]` on a known value becomes the difference to the value set next, so `[-]+++++` on a
+/// known 3 is `++`, and loops on a known zero are removed. Multiplication loops on a known value
+/// are unrolled if `level` accepts the change in length. After a loop only the cells it writes
+/// are forgotten and its cell is zero, unless the loop moves the pointer, then nothing is known.
+/// Separators mark points where the temporary cells left of the start are zero, as `#` checks
+/// that. Separators inside removed loops are kept, but say nothing about the temporary cells.
+fn propagate_constants(nodes: Vec<Node>, tape: &mut Tape, level: OptLevel) -> Vec<Node> {
+    let mut out = Vec::new();
+    let mut nodes = nodes.into_iter().peekable();
+
+    while let Some(node) = nodes.next() {
+        match node {
+            Node::Add(n) => {
+                tape.set_current(tape.current().map(|v| v + n as i64));
+                out.push(node);
+            }
+            Node::Move(n) => {
+                tape.position += n;
+                out.push(node);
+            }
+            Node::Input => {
+                tape.set_current(None);
+                out.push(node);
+            }
+            Node::Output | Node::DeadSeparator => out.push(node),
+            Node::Separator => {
+                if tape.absolute {
+                    for i in 1..=CHECKED_TEMPS as isize {
+                        tape.set(-i, Some(0));
                     }
                 }
-                ']' => {
-                    bracket_index -= 1;
-                    if !used_variables.contains(&pointer) {
-                        used_variables.push(pointer)
+                out.push(node);
+            }
+            Node::Clear => {
+                match tape.current() {
+                    Some(0) => {}
+                    Some(v) => {
+                        let next = match nodes.peek() {
+                            Some(Node::Add(k)) => *k as i64,
+                            _ => 0,
+                        };
+                        if (next - v).abs() < 3 + next.abs() {
+                            out.push(Node::Add(-v as i32));
+                        } else {
+                            out.push(Node::Clear);
+                        }
                     }
+                    None => out.push(Node::Clear),
                 }
-                ',' | '.' | '+' | '-' => {
-                    if !used_variables.contains(&pointer) {
-                        used_variables.push(pointer)
+                tape.set_current(Some(0));
+            }
+            Node::MulLoop(ref targets) => {
+                let value = tape.current();
+                if value == Some(0) {
+                    continue;
+                }
+                for &(offset, factor) in targets {
+                    let cell = tape.position + offset;
+                    let result = value
+                        .zip(tape.get(cell))
+                        .and_then(|(v, t)| t.checked_add(v.checked_mul(factor as i64)?));
+                    tape.set(cell, result);
+                }
+                tape.set_current(Some(0));
+
+                match value.and_then(|v| unroll(v, targets)) {
+                    Some(unrolled)
+                        if level.accept_faster(
+                            emit(std::slice::from_ref(&node)).len(),
+                            emit(&unrolled).len(),
+                        ) =>
+                    {
+                        out.extend(unrolled)
+                    }
+                    _ => out.push(node),
+                }
+            }
+            Node::Loop(body) => {
+                if tape.current() == Some(0) {
+                    keep_separators(&Node::Loop(body), &mut out);
+                    continue;
+                }
+                match loop_writes(&body) {
+                    Some(writes) => {
+                        for offset in writes {
+                            tape.set(tape.position + offset, None);
+                        }
+                        tape.set_current(None);
                     }
-                },
-                '#' => {
-                    // Remove all pointers in `used_variables` if they're temporary
-                    used_variables.retain(|x| *x > 0);
+                    None => tape.forget(),
                 }
-                _ => {},
+                let body = propagate_constants(body, &mut tape.clone(), level);
+                out.push(Node::Loop(body));
+                tape.set_current(Some(0));
             }
         }
+    }
 
-        output_code += code;
-        let mut should_add = true;
+    out
+}
 
-        // Do not add if the variable has not been used anytime
-        // Or is temporary and a new instruction has just been generated (viz match case above)
-        // Limited to code that is not inside any bracket because iteration makes it invalid
-        if !used_variables.contains(&pointer) && bracket_index == 0 {
-            should_add = false;
-        }
+/// Straight-line code with the same effect as a multiplication loop on a cell known to be `value`
+fn unroll(value: i64, targets: &[(isize, i32)]) -> Option<Vec<Node>> {
+    let mut nodes = Vec::new();
+    let mut position = 0;
+
+    for &(offset, factor) in targets {
+        nodes.push(Node::Move(offset - position));
+        nodes.push(Node::Add(
+            i32::try_from(value.checked_mul(factor as i64)?).ok()?,
+        ));
+        position = offset;
+    }
+    nodes.push(Node::Move(-position));
+    nodes.push(Node::Add(i32::try_from(-value).ok()?));
+
+    Some(nodes)
+}
+
+/// Offsets of all cells a loop body can change, or `None` if the loop moves the pointer
+fn loop_writes(body: &[Node]) -> Option<BTreeSet<isize>> {
+    let mut writes = BTreeSet::new();
+    let mut offset = 0;
 
-        // output_code += &*format!("{debug_index}");
-        if should_add {
-            output_code += "[-]";
-            used_variables.retain(|x| *x != pointer);
+    for node in body {
+        match node {
+            Node::Add(_) | Node::Input | Node::Clear => {
+                writes.insert(offset);
+            }
+            Node::Move(n) => offset += n,
+            Node::MulLoop(targets) => {
+                writes.insert(offset);
+                writes.extend(targets.iter().map(|(o, _)| offset + o));
+            }
+            Node::Loop(inner) => {
+                writes.extend(loop_writes(inner)?.into_iter().map(|o| offset + o));
+            }
+            Node::Output | Node::Separator | Node::DeadSeparator => {}
         }
     }
 
-    output_code
+    (offset == 0).then_some(writes)
+}
+
+fn keep_separators(node: &Node, out: &mut Vec<Node>) {
+    match node {
+        Node::Separator | Node::DeadSeparator => out.push(Node::DeadSeparator),
+        Node::Loop(body) => body.iter().for_each(|n| keep_separators(n, out)),
+        _ => {}
+    }
+}
+
+/// Removes all non-brainfuck characters from the input
+pub fn remove_non_brainfuck(bf: String) -> String {
+    bf.chars()
+        .filter(|&c| "+-><[].,".contains(c))
+        .collect::<String>()
+        .trim()
+        .to_string()
 }
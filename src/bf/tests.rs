@@ -0,0 +1,282 @@
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod tests {
+    use crate::bf::interpreter::{
+        CellWidth, EofPolicy, ExecutionReport, Interpreter, InterpreterConfig, OverflowPolicy,
+        PointerPolicy, RuntimeError,
+    };
+    use crate::bf::ops::{Op, Program};
+    use crate::bf::optim::optimize_tree;
+    use crate::pipeline::OptLevel;
+
+    #[test]
+    fn lowering() {
+        let program = Program::compile("+++-->>><[-]").unwrap();
+        assert_eq!(
+            program.ops,
+            vec![
+                Op::Add(3),
+                Op::Add(-2),
+                Op::Move(3),
+                Op::Move(-1),
+                Op::Clear
+            ]
+        );
+        assert_eq!(program.positions, vec![0, 3, 5, 8, 9]);
+
+        // Multiply loops, counting down and up
+        let program = Program::compile("[->++>+++<<]").unwrap();
+        assert_eq!(
+            program.ops,
+            vec![
+                Op::MulAdd {
+                    offset: 1,
+                    factor: 2
+                },
+                Op::MulAdd {
+                    offset: 2,
+                    factor: 3
+                },
+                Op::Clear
+            ]
+        );
+        let program = Program::compile("[<-+>- comment <+>]").unwrap();
+        assert_eq!(
+            program.ops,
+            vec![
+                Op::MulAdd {
+                    offset: -1,
+                    factor: 1
+                },
+                Op::Clear
+            ]
+        );
+
+        assert_eq!(
+            Program::compile("[>>]").unwrap().ops,
+            vec![Op::ScanRight(2)]
+        );
+        assert_eq!(Program::compile("[<]").unwrap().ops, vec![Op::ScanLeft(1)]);
+
+        // Loops that are not idioms are kept as jumps
+        let program = Program::compile(">[-.]<#").unwrap();
+        assert_eq!(
+            program.ops,
+            vec![
+                Op::Move(1),
+                Op::JumpIfZero(4),
+                Op::Add(-1),
+                Op::Output,
+                Op::JumpIfNonZero(1),
+                Op::Move(-1),
+                Op::CheckTemps
+            ]
+        );
+    }
+
+    #[test]
+    fn run() {
+        #[track_caller]
+        fn assert_output(code: &str, input: &str, expected: &[u8]) {
+            let mut stdin = input.as_bytes();
+            let mut stdout = Vec::new();
+            Interpreter::new()
+                .run(code, &mut stdin, &mut stdout)
+                .unwrap();
+            assert_eq!(stdout, expected);
+        }
+
+        // 8 * 8 + 1 = 'A'
+        assert_output("++++++++[->++++++++<]>+.", "", b"A");
+        // Counting up is not a multiply loop, but still wraps around
+        assert_eq!(Program::compile("[+>++<]").unwrap().ops.len(), 6);
+        assert_output("+++[+>++<]>.", "", &[250]);
+        // Scan back to the first empty cell
+        assert_output(">+>+>+[<]>.<.", "", &[1, 0]);
+        // Cat until EOF
+        assert_output(",[.,]", "Hello", b"Hello");
+        // Pointer wraps around the left end of the tape
+        assert_output("<+++.>.", "", &[3, 0]);
+    }
+
+    #[test]
+    fn config() {
+        #[track_caller]
+        fn assert_output(config: InterpreterConfig, code: &str, input: &str, expected: &[u8]) {
+            let mut stdin = input.as_bytes();
+            let mut stdout = Vec::new();
+            Interpreter::with_config(config)
+                .run(code, &mut stdin, &mut stdout)
+                .unwrap();
+            assert_eq!(stdout, expected);
+        }
+
+        let default = InterpreterConfig::default();
+
+        // 16 bit cells do not wrap at 256
+        let wide = InterpreterConfig {
+            cell_width: CellWidth::U16,
+            ..default
+        };
+        let code = format!("{}[[-]>+<]>.", "+".repeat(256));
+        assert_output(wide, &code, "", &[1]);
+        assert_output(default, &code, "", &[0]);
+        assert_output(wide, "-.", "", &[255]);
+
+        // EOF policies
+        let eof = |eof| InterpreterConfig { eof, ..default };
+        assert_output(eof(EofPolicy::Zero), "+,.", "", &[0]);
+        assert_output(eof(EofPolicy::Max), "+,.", "", &[255]);
+        assert_output(eof(EofPolicy::Unchanged), "+,.", "", &[1]);
+        assert_output(eof(EofPolicy::Unchanged), "+,.", "A", b"A");
+
+        // A tiny tape grows in both directions, keeping cell zero in place
+        let grow = InterpreterConfig {
+            tape_length: 1,
+            pointer: PointerPolicy::Grow,
+            ..default
+        };
+        assert_output(grow, "+<<<++>>>>>+++<<.>>.<<<<<.", "", &[1, 3, 2]);
+
+        // A tiny tape wraps around
+        let wrap = InterpreterConfig {
+            tape_length: 2,
+            ..default
+        };
+        assert_output(wrap, "+>>.", "", &[1]);
+    }
+
+    #[test]
+    fn errors() {
+        fn run(config: InterpreterConfig, code: &str) -> Result<ExecutionReport, RuntimeError> {
+            Interpreter::with_config(config).run(code, &mut "".as_bytes(), &mut Vec::new())
+        }
+
+        let default = InterpreterConfig::default();
+
+        assert!(matches!(
+            run(default, "+[-]]"),
+            Err(RuntimeError::UnbalancedBracket { position: 4 })
+        ));
+        assert!(matches!(
+            run(default, "+[[-]"),
+            Err(RuntimeError::UnbalancedBracket { position: 1 })
+        ));
+
+        let limited = InterpreterConfig {
+            step_limit: 10,
+            ..default
+        };
+        assert_eq!(run(limited, "+[-]").unwrap().steps, 2);
+        assert!(matches!(
+            run(limited, "+[]"),
+            Err(RuntimeError::StepLimitExceeded { limit: 10, .. })
+        ));
+
+        // Temporary variables are left of cell zero
+        assert!(matches!(
+            run(default, "<+>#"),
+            Err(RuntimeError::InvariantViolation { position: 3, .. })
+        ));
+        assert!(run(default, "<+-># >+<#").is_ok());
+
+        let overflow = InterpreterConfig {
+            overflow: OverflowPolicy::Error,
+            ..default
+        };
+        assert!(run(overflow, &"+".repeat(255)).is_ok());
+        assert!(matches!(
+            run(overflow, &"+".repeat(256)),
+            Err(RuntimeError::CellOverflow {
+                cell: 0,
+                value: 256,
+                ..
+            })
+        ));
+        assert!(matches!(
+            run(overflow, ">-"),
+            Err(RuntimeError::CellOverflow {
+                cell: 1,
+                value: -1,
+                position: 1
+            })
+        ));
+
+        let pointer = InterpreterConfig {
+            pointer: PointerPolicy::Error,
+            ..default
+        };
+        assert!(run(pointer, "><").is_ok());
+        assert!(matches!(
+            run(pointer, "><<"),
+            Err(RuntimeError::PointerOutOfBounds {
+                cell: -1,
+                position: 1
+            })
+        ));
+        let empty = InterpreterConfig {
+            tape_length: 0,
+            ..default
+        };
+        assert!(matches!(run(empty, "+"), Err(RuntimeError::EmptyTape)));
+
+        // Transfer loops that never run don't touch the cells they would move to
+        assert!(run(pointer, "[-<+>]").is_ok());
+        assert!(matches!(
+            run(pointer, "+[-<+>]"),
+            Err(RuntimeError::PointerOutOfBounds { cell: -1, .. })
+        ));
+
+        struct Broken;
+        impl std::io::Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        assert!(matches!(
+            Interpreter::new().run("+.", &mut "".as_bytes(), &mut Broken),
+            Err(RuntimeError::Io { position: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn tree_optimizer() {
+        let o2 = |bf: &str| optimize_tree(bf.to_string(), OptLevel::O2);
+
+        // Moves that cancel out are folded, also across loops
+        assert_eq!(o2("+>-<+"), "++>-");
+        assert_eq!(o2(">+<<+>"), ">+<<+");
+        // Loops right after the start or a clear can never run
+        assert_eq!(o2("[->+<]+"), "+");
+        assert_eq!(o2(",[-][>+<]"), ",[-]");
+        // Separators are never removed, pointer moves around them are folded. Those of removed
+        // loops no longer check the temporary cells
+        assert_eq!(o2("[#]#+>#"), "$#+#");
+        assert_eq!(o2("+>#<-#"), "+#-#");
+        // Clears of known values become the difference to the next value
+        assert_eq!(o2("+++[-]+++++."), "+++++.");
+        assert_eq!(o2(">,<+++[-]+++++>[<+>-]"), ">,<+++++>[-<+>]");
+        // Only the cells a loop writes are forgotten, the loop cell is zero after it
+        assert_eq!(o2(">+<,[>>+<<-]>[-]>[-]"), ">+<,[->>+<<]>->[-]");
+        // Loops that move the pointer make everything unknown
+        assert_eq!(o2("+,[>]<[-]"), "+,[>]<[-]");
+        // Temporary cells are zero at separators
+        assert_eq!(o2(",[<+>-]#<[-]+"), ",[-<+>]#<+");
+        // Only the faster levels unroll multiplication loops on known values into longer code
+        let multiply = "++++++++++[->+++++<]";
+        assert_eq!(o2(multiply), multiply);
+        assert_eq!(optimize_tree(multiply.to_string(), OptLevel::Os), multiply);
+        assert_eq!(
+            optimize_tree(multiply.to_string(), OptLevel::O3),
+            format!(">{}", "+".repeat(50))
+        );
+        assert_eq!(o2("++[->+<]"), ">++");
+        // O1 doesn't know values
+        assert_eq!(optimize_tree("[-]+".to_string(), OptLevel::O1), "[-]+");
+        // Unbalanced input is left alone
+        assert_eq!(o2("+["), "+[");
+    }
+}
@@ -0,0 +1,405 @@
+use crate::bf::interpreter::{Interpreter, InterpreterConfig, RuntimeError};
+use crate::lir::analysis::{byte_names, InstructionsAnalysis};
+use crate::lir::sourcemap::{SourceMap, SourceMapEntry};
+use anyhow::Result;
+use std::collections::{BTreeSet, HashSet};
+use std::io::{BufRead, Read, Write};
+
+mod tests;
+
+const HELP: &str = "\
+Commands:
+  break <line>     stop before the instruction on a line (b)
+  delete <line>    remove a breakpoint (d)
+  continue         run until a breakpoint or the end (c)
+  step [n]         run n LIR instructions (s)
+  stepi [n]        run n brainfuck ops (si)
+  print <var>      show a variable (p)
+  watch <var>      show a variable at every stop (w)
+  unwatch <var>    stop showing a variable at every stop
+  vars             show all variables, temporaries and the stack (v)
+  tape [cell] [n]  show n cells starting at a cell, around the pointer by default (t)
+  where            show the current instruction (l)
+  help             show this message (h)
+  quit             exit (q)";
+
+/// Number of temporary variables shown by `vars`
+const SHOWN_TEMPS: isize = 17;
+
+/// Why execution stopped
+enum Stop {
+    Paused,
+    Breakpoint,
+    Finished,
+    Error(RuntimeError),
+}
+
+/// Step debugger for generated brainfuck, driven by text commands
+pub struct Debugger {
+    interpreter: Interpreter,
+    source_map: SourceMap,
+    analysis: InstructionsAnalysis,
+    /// Source map entry of every op of the loaded program
+    op_entries: Vec<Option<usize>>,
+    breakpoints: BTreeSet<usize>,
+    watches: Vec<String>,
+    finished: bool,
+    /// Whether the program's output so far ends with a newline, or is empty
+    at_line_start: bool,
+}
+
+/// Writer that remembers whether the last byte written was a newline
+struct LineTracker<'a, W: Write> {
+    inner: &'a mut W,
+    at_line_start: bool,
+}
+
+impl<W: Write> Write for LineTracker<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(&last) = buf[..n].last() {
+            self.at_line_start = last == b'\n';
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Debugger {
+    pub fn new(
+        code: &str,
+        config: InterpreterConfig,
+        source_map: SourceMap,
+        analysis: InstructionsAnalysis,
+    ) -> Result<Self, RuntimeError> {
+        let mut interpreter = Interpreter::with_config(config);
+        interpreter.load(code)?;
+
+        let op_entries = interpreter
+            .program()
+            .positions
+            .iter()
+            .map(|&p| source_map.lookup_index(p))
+            .collect();
+
+        Ok(Self {
+            interpreter,
+            source_map,
+            analysis,
+            op_entries,
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+            finished: false,
+            at_line_start: true,
+        })
+    }
+
+    /// Read commands until `quit` or the end of `commands`
+    ///
+    /// The program reads from `input`, its output is interleaved with the debugger's in `output`.
+    pub fn run(
+        &mut self,
+        commands: impl BufRead,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        writeln!(output, "Type `help` for a list of commands")?;
+        self.show_location(output)?;
+
+        let mut lines = commands.lines();
+        loop {
+            write!(output, "(alkoholiq) ")?;
+            output.flush()?;
+
+            let Some(line) = lines.next() else {
+                writeln!(output)?;
+                return Ok(());
+            };
+            let line = line?;
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let args: Vec<&str> = words.collect();
+            let count = args.first().and_then(|n| n.parse().ok()).unwrap_or(1);
+
+            match command {
+                "break" | "b" => match args.first().and_then(|l| l.parse().ok()) {
+                    Some(line) => {
+                        if !self.source_map.entries.iter().any(|e| e.line == line) {
+                            writeln!(output, "Warning: no instruction on line {line}")?;
+                        }
+                        self.breakpoints.insert(line);
+                        writeln!(output, "Breakpoint on line {line}")?;
+                    }
+                    None => writeln!(output, "Usage: break <line>")?,
+                },
+                "delete" | "d" => match args.first().and_then(|l| l.parse().ok()) {
+                    Some(line) if self.breakpoints.remove(&line) => {
+                        writeln!(output, "Removed breakpoint on line {line}")?
+                    }
+                    _ => writeln!(output, "No such breakpoint")?,
+                },
+                "continue" | "c" => {
+                    let stop = self
+                        .resume(input, output, |d, prev| {
+                            d.entry_changed(prev) && d.at_breakpoint()
+                        })
+                        .unwrap_or(Stop::Breakpoint);
+                    self.report(stop, output)?;
+                }
+                "step" | "s" => {
+                    let stop = (0..count)
+                        .find_map(|_| self.resume(input, output, |d, prev| d.entry_changed(prev)))
+                        .unwrap_or(Stop::Paused);
+                    self.report(stop, output)?;
+                }
+                "stepi" | "si" => {
+                    let stop = (0..count)
+                        .find_map(|_| self.resume(input, output, |_, _| true))
+                        .unwrap_or(Stop::Paused);
+                    self.report(stop, output)?;
+                }
+                "print" | "p" => {
+                    for var in &args {
+                        self.show_variable(var, output)?;
+                    }
+                }
+                "watch" | "w" => {
+                    for var in args {
+                        self.show_variable(var, output)?;
+                        self.watches.push(var.to_string());
+                    }
+                }
+                "unwatch" => self.watches.retain(|w| !args.contains(&w.as_str())),
+                "vars" | "v" => self.show_variables(output)?,
+                "tape" | "t" => {
+                    let pointer = self.interpreter.pointer();
+                    let start = args
+                        .first()
+                        .and_then(|c| c.parse().ok())
+                        .unwrap_or(pointer - 4);
+                    let len: isize = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(9);
+                    for cell in start..start + len {
+                        let marker = if cell == pointer { ">" } else { " " };
+                        writeln!(output, "{marker}{cell:>6}: {}", self.interpreter.cell(cell))?;
+                    }
+                }
+                "where" | "l" => self.show_location(output)?,
+                "help" | "h" => writeln!(output, "{HELP}")?,
+                "quit" | "q" => return Ok(()),
+                _ => writeln!(output, "Unknown command `{command}`, type `help` for help")?,
+            }
+        }
+    }
+
+    /// Execute ops until `should_stop` returns true after an op
+    ///
+    /// `should_stop` gets the source map entry of the last op executed. Returns `None` if it
+    /// stopped execution, otherwise why the program ended.
+    fn resume(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        should_stop: impl Fn(&Self, Option<usize>) -> bool,
+    ) -> Option<Stop> {
+        if self.finished {
+            return Some(Stop::Finished);
+        }
+
+        let mut output = LineTracker {
+            inner: output,
+            at_line_start: self.at_line_start,
+        };
+
+        loop {
+            let prev = self.current_entry();
+            let step = self.interpreter.step(input, &mut output);
+            self.at_line_start = output.at_line_start;
+            match step {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.finished = true;
+                    return Some(Stop::Finished);
+                }
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Stop::Error(e));
+                }
+            }
+
+            if should_stop(self, prev) {
+                return None;
+            }
+        }
+    }
+
+    fn report(&mut self, stop: Stop, output: &mut impl Write) -> Result<()> {
+        if !self.at_line_start {
+            writeln!(output)?;
+            self.at_line_start = true;
+        }
+
+        match stop {
+            Stop::Finished => writeln!(output, "Program finished")?,
+            Stop::Error(e) => {
+                writeln!(output, "Runtime error: {e}")?;
+                if let Some(entry) = self.source_map.preceding(e.position()) {
+                    writeln!(output, "  at line {}: `{}`", entry.line, entry.source)?;
+                }
+            }
+            Stop::Breakpoint => {
+                write!(output, "Breakpoint, ")?;
+                self.show_location(output)?;
+            }
+            Stop::Paused => self.show_location(output)?,
+        }
+
+        for var in &self.watches {
+            self.show_variable(var, output)?;
+        }
+
+        Ok(())
+    }
+
+    /// Source map entry of the op that executes next
+    fn current_entry(&self) -> Option<usize> {
+        self.op_entries
+            .get(self.interpreter.instruction_index())
+            .copied()
+            .flatten()
+    }
+
+    fn current(&self) -> Option<&SourceMapEntry> {
+        self.current_entry().map(|e| &self.source_map.entries[e])
+    }
+
+    /// Whether execution moved on to the code of another instruction
+    fn entry_changed(&self, prev: Option<usize>) -> bool {
+        let current = self.current_entry();
+        current.is_some() && current != prev
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.current()
+            .is_some_and(|e| self.breakpoints.contains(&e.line))
+    }
+
+    fn show_location(&self, output: &mut impl Write) -> Result<()> {
+        if self.finished {
+            return Ok(writeln!(output, "Program finished")?);
+        }
+
+        match self.current() {
+            Some(entry) => writeln!(
+                output,
+                "line {}: `{}` (op {}, {} steps)",
+                entry.line,
+                entry.source,
+                self.interpreter.instruction_index(),
+                self.interpreter.steps()
+            )?,
+            None => writeln!(
+                output,
+                "between instructions (op {}, {} steps)",
+                self.interpreter.instruction_index(),
+                self.interpreter.steps()
+            )?,
+        }
+
+        Ok(())
+    }
+
+    fn show_variable(&self, var: &str, output: &mut impl Write) -> Result<()> {
+        if let Some(&(cell, len)) = self.analysis.arrays.get(var) {
+            writeln!(output, "{var} = {}", self.format_array(cell, len))?;
+            return Ok(());
+        }
+        match self.analysis.variables.get(var) {
+            Some(&cell) => writeln!(output, "{var} = {}", self.format_variable(var, cell))?,
+            None => writeln!(output, "No variable named `{var}`")?,
+        }
+        Ok(())
+    }
+
+    fn show_variables(&self, output: &mut impl Write) -> Result<()> {
+        // The upper cells of wide variables are shown as part of the variable
+        let upper: HashSet<String> = self
+            .analysis
+            .widths
+            .iter()
+            .flat_map(|(name, &bytes)| byte_names(name, bytes).into_iter().skip(1))
+            .collect();
+        let mut variables: Vec<(&String, &i32)> = self
+            .analysis
+            .variables
+            .iter()
+            .filter(|(name, &cell)| cell >= 0 && !upper.contains(*name))
+            .collect();
+        variables.sort_by_key(|(_, &cell)| cell);
+
+        writeln!(output, "Variables:")?;
+        for (name, &cell) in variables {
+            writeln!(output, "  {name} = {}", self.format_variable(name, cell))?;
+        }
+
+        let mut arrays: Vec<_> = self.analysis.arrays.iter().collect();
+        arrays.sort_by_key(|(_, &(cell, _))| cell);
+        for (name, &(cell, len)) in arrays {
+            writeln!(output, "  {name} = {}", self.format_array(cell, len))?;
+        }
+
+        let temps = (1..=SHOWN_TEMPS)
+            .map(|i| format!("{}={}", i - 1, self.interpreter.cell(-i)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(output, "Temporaries: {temps}")?;
+
+        // Stack entries are a flag followed by a value, starting two cells after the variables
+        let mut stack = Vec::new();
+        let mut cell = self.analysis.variable_count as isize + 2;
+        while self.interpreter.cell(cell) != 0 {
+            stack.push(self.interpreter.cell(cell + 1).to_string());
+            cell += 2;
+        }
+        writeln!(output, "Stack (bottom first): [{}]", stack.join(", "))?;
+
+        Ok(())
+    }
+
+    /// Elements of the array at `cell`, see [`crate::lir::analysis::array_cursor`] for the layout
+    fn format_array(&self, cell: i32, len: u8) -> String {
+        let elements: Vec<String> = (0..len as isize)
+            .map(|i| self.interpreter.cell(cell as isize + 5 + 3 * i).to_string())
+            .collect();
+        format!("[{}]", elements.join(", "))
+    }
+
+    /// A variable, combining the cells of a wide variable lowest first and showing signed
+    /// variables in two's complement
+    fn format_variable(&self, var: &str, cell: i32) -> String {
+        if self.analysis.signed.contains(var) {
+            return (self.interpreter.cell(cell as isize) as u8 as i8).to_string();
+        }
+        match self.analysis.widths.get(var) {
+            Some(&bytes) => (0..bytes as isize)
+                .rev()
+                .fold(0u64, |value, i| {
+                    value << 8 | self.interpreter.cell(cell as isize + i) as u64
+                })
+                .to_string(),
+            None => self.format_cell(cell as isize),
+        }
+    }
+
+    fn format_cell(&self, cell: isize) -> String {
+        let value = self.interpreter.cell(cell);
+        match char::from_u32(value) {
+            Some(c) if c.is_ascii_graphic() || c == ' ' => format!("{value} '{c}'"),
+            _ => value.to_string(),
+        }
+    }
+}
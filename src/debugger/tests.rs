@@ -0,0 +1,94 @@
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod tests {
+    use crate::bf::interpreter::InterpreterConfig;
+    use crate::debugger::Debugger;
+    use crate::pipeline::{compile, OptLevel};
+
+    /// Run a debugging session and return everything it printed
+    fn session(code: &str, level: OptLevel, commands: &str, input: &str) -> String {
+        let compiled = compile(code, level).unwrap();
+
+        let mut debugger = Debugger::new(
+            &compiled.code,
+            InterpreterConfig::default(),
+            compiled.source_map,
+            compiled.analysis,
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        debugger
+            .run(commands.as_bytes(), &mut input.as_bytes(), &mut output)
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    const CODE: &str = "read a
+set b 3
+while_nz b
+    print a
+    inc a
+    push a
+    dec b
+end
+prints 'done'
+";
+
+    #[test]
+    fn breakpoints_and_variables() {
+        for level in [OptLevel::O0, OptLevel::O2, OptLevel::O3] {
+            let output = session(CODE, level, "b 5\nc\nc\np a b\nvars\nq\n", "A");
+
+            // Stops once before and once after the first iteration printed `A`
+            assert!(output.contains("Breakpoint, line 5: `inc a`"), "{output}");
+            assert!(output.contains("A\nBreakpoint, line 5"), "{output}");
+            assert!(output.contains("B\nBreakpoint, line 5"), "{output}");
+            assert!(output.contains("a = 66 'B'\nb = 2\n"), "{output}");
+            assert!(output.contains("Stack (bottom first): [66]"), "{output}");
+            assert!(output.contains("Temporaries: 0=0 1=0"), "{output}");
+        }
+    }
+
+    #[test]
+    fn stepping() {
+        let output = session(CODE, OptLevel::O0, "w b\ns\ns 2\nsi\nl\nc\ns\n", "A");
+
+        assert!(output.contains("line 2: `set b 3`"), "{output}");
+        assert!(output.contains("line 4: `print a`"), "{output}");
+        assert!(output.contains("b = 3\n"), "{output}");
+        assert!(output.contains("ABCdone\nProgram finished"), "{output}");
+        assert!(
+            output.ends_with("Program finished\nb = 0\n(alkoholiq) \n"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        let code = "array xs 3\nset i 1\nset v 7\nstore xs i v\nprints 'done'\n";
+        let output = session(code, OptLevel::O2, "b 5\nc\np xs\nvars\nq\n", "");
+
+        assert!(output.contains("xs = [0, 7, 0]\n"), "{output}");
+        assert!(output.contains("  xs = [0, 7, 0]\n"), "{output}");
+    }
+
+    #[test]
+    fn wide_and_signed_variables() {
+        let code = "var16 n\nset n 1000\nsvar s\nset s -3\nprints 'done'\n";
+        let output = session(code, OptLevel::O2, "b 5\nc\np n\np s\nvars\nq\n", "");
+
+        assert!(output.contains("n = 1000\n"), "{output}");
+        assert!(output.contains("  s = -3\n"), "{output}");
+        assert!(!output.contains("n.1"), "{output}");
+    }
+
+    #[test]
+    fn runtime_error() {
+        let output = session("set a 1\nraw '<+>'\nset b 1", OptLevel::O0, "c\n", "");
+        assert!(
+            output.contains("Runtime error: Invariant violated"),
+            "{output}"
+        );
+        assert!(output.contains("at line 2: `raw '<+>'`"), "{output}");
+    }
+}
@@ -1,142 +1,377 @@
-use crate::lir::instruction::{Instruction, Instruction::*, Variable};
+use crate::bf::interpreter::TRAP_CELL;
+use crate::lir::instruction::{Immediate, Instruction, Instruction::*, Variable};
 use anyhow::Result;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 
+/// First cell and length of every array
+pub type Arrays = HashMap<String, (i32, Immediate)>;
+
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct InstructionsAnalysis {
     instructions: Vec<Instruction>,
     pub variables: HashMap<String, i32>,
     pub variable_count: i32,
+    pub arrays: Arrays,
+    /// Number of cells of every variable wider than one cell
+    pub widths: HashMap<String, u8>,
+    /// Variables declared with `svar`
+    pub signed: HashSet<String>,
+    /// Indices of the loops that keep their state in [`loop_flag`], which are the loops `break`
+    /// and `continue` jump out of and the loops on wide variables
+    pub flag_loops: HashSet<usize>,
 }
 
+/// An error in the instruction at index `instruction`
 #[derive(Debug, Clone, Error)]
-enum InstructionError {
+pub enum InstructionError {
     #[error("Invalid variable name: {v}")]
-    InvalidVariableName { v: Variable },
+    InvalidVariableName { v: Variable, instruction: usize },
 
     #[error("Variable {v} must be assigned before use")]
-    VariableMustBeAssigned { v: Variable },
+    VariableMustBeAssigned { v: Variable, instruction: usize },
+
+    #[error("`end` without a block to end")]
+    UnmatchedEnd { instruction: usize },
+
+    /// Points at the instruction that opened the block
+    #[error("Block is never closed with `end`")]
+    UnclosedBlock { instruction: usize },
+
+    #[error("`case` outside of a `match`")]
+    CaseOutsideMatch { instruction: usize },
+
+    #[error("The constants of a `match` must be in ascending order")]
+    UnsortedMatch { instruction: usize },
+
+    #[error("`else` outside of an if block or after another `else`")]
+    ElseOutsideIf { instruction: usize },
 
-    #[error("Uneven amount of blocks")]
-    UnevenAmountOfBlocks(),
+    #[error("`break` and `continue` need a depth from 1 to the number of loops they are in")]
+    JumpOutsideLoop { instruction: usize },
+
+    #[error("Array {v} must be declared with `array` before use")]
+    UnknownArray { v: Variable, instruction: usize },
+
+    #[error("{v} is already declared as a variable or an array")]
+    NameInUse { v: Variable, instruction: usize },
+
+    #[error("Arrays need a length from 1 to 255")]
+    InvalidArrayLength { instruction: usize },
+
+    #[error("{v} is wider than 8 bits, which this instruction does not support")]
+    WideVariable { v: Variable, instruction: usize },
+
+    #[error("{v} is signed and {other} is not, `cast` one of them to mix them")]
+    MixedSignedness {
+        v: Variable,
+        other: Variable,
+        instruction: usize,
+    },
+}
+
+/// Every error found in a program, in the order of the instructions they are in
+#[derive(Debug, Clone, Error)]
+#[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+pub struct AnalysisError(pub Vec<InstructionError>);
+
+impl InstructionError {
+    pub fn instruction(&self) -> usize {
+        match self {
+            InstructionError::InvalidVariableName { instruction, .. }
+            | InstructionError::VariableMustBeAssigned { instruction, .. }
+            | InstructionError::UnmatchedEnd { instruction }
+            | InstructionError::UnclosedBlock { instruction }
+            | InstructionError::CaseOutsideMatch { instruction }
+            | InstructionError::UnsortedMatch { instruction }
+            | InstructionError::ElseOutsideIf { instruction }
+            | InstructionError::JumpOutsideLoop { instruction }
+            | InstructionError::UnknownArray { instruction, .. }
+            | InstructionError::NameInUse { instruction, .. }
+            | InstructionError::WideVariable { instruction, .. }
+            | InstructionError::MixedSignedness { instruction, .. }
+            | InstructionError::InvalidArrayLength { instruction } => *instruction,
+        }
+    }
+
+    /// The variable the error is about
+    pub fn variable(&self) -> Option<&str> {
+        match self {
+            InstructionError::InvalidVariableName { v, .. }
+            | InstructionError::VariableMustBeAssigned { v, .. }
+            | InstructionError::UnknownArray { v, .. }
+            | InstructionError::NameInUse { v, .. }
+            | InstructionError::WideVariable { v, .. }
+            | InstructionError::MixedSignedness { v, .. } => Some(v),
+            InstructionError::UnmatchedEnd { .. }
+            | InstructionError::UnclosedBlock { .. }
+            | InstructionError::CaseOutsideMatch { .. }
+            | InstructionError::UnsortedMatch { .. }
+            | InstructionError::ElseOutsideIf { .. }
+            | InstructionError::JumpOutsideLoop { .. }
+            | InstructionError::InvalidArrayLength { .. } => None,
+        }
+    }
 }
 
 impl InstructionsAnalysis {
+    /// Analyze a program, returning an [`AnalysisError`] with all errors in it
     pub fn new(instructions: Vec<Instruction>) -> Result<Self> {
-        Self::sanity_check(instructions.clone())?;
+        let mut errors = Vec::new();
+        let mut analysis = Self {
+            flag_loops: Self::sanity_check(instructions.clone(), &mut errors),
+            instructions,
+            ..Default::default()
+        };
+        analysis.build_variable_hashmap(&mut errors);
 
-        let (variables, variable_count) = Self::build_variable_hashmap(instructions.clone())?;
+        if !errors.is_empty() {
+            errors.sort_by_key(InstructionError::instruction);
+            return Err(AnalysisError(errors).into());
+        }
 
-        Ok(Self {
-            instructions,
-            variables,
-            variable_count,
-        })
+        Ok(analysis)
     }
 
-    fn build_variable_hashmap(input: Vec<Instruction>) -> Result<(HashMap<String, i32>, i32)> {
+    fn build_variable_hashmap(&mut self, errors: &mut Vec<InstructionError>) {
         let mut variables = HashMap::new();
+        let mut arrays = HashMap::new();
+        let mut widths = HashMap::new();
+        let mut signed = HashSet::new();
         let mut index = 0;
+        // Number of open blocks
+        let mut depth = 0;
 
-        let mut var = |v: Variable, must_be_defined: bool| {
-            if must_be_defined && !variables.contains_key(&v) {
-                return Err(InstructionError::VariableMustBeAssigned { v });
+        // Register and check validity of variable accesses
+        for (instruction, i) in self.instructions.clone().into_iter().enumerate() {
+            if i.opens_block() {
+                depth += 1;
             }
-
-            if variables.contains_key(&v) {
-                return Ok(());
+            // Conditions on wide variables take too many instructions to check at `]`
+            if i.is_loop() && i.variables().iter().any(|v| widths.contains_key(*v)) {
+                self.flag_loops.insert(instruction);
             }
-
-            // Variables must start with a letter and can contain alpha, _-, and digits
-            if !v.chars().next().unwrap().is_alphabetic() {
-                return Err(InstructionError::InvalidVariableName { v });
+            if self.flag_loops.contains(&instruction) {
+                Self::register_hidden(&mut variables, &mut index, loop_flag(depth));
+            }
+            if let Instruction::Wide { name, bytes } = &i {
+                if variables.contains_key(name) || arrays.contains_key(name) {
+                    errors.push(InstructionError::NameInUse {
+                        v: name.clone(),
+                        instruction,
+                    });
+                } else if !valid_name(name) {
+                    errors.push(InstructionError::InvalidVariableName {
+                        v: name.clone(),
+                        instruction,
+                    });
+                } else {
+                    Self::register_wide(&mut variables, &mut index, &mut widths, name, *bytes);
+                }
+            }
+            if let Instruction::Signed(name) = &i {
+                if variables.contains_key(name) || arrays.contains_key(name) {
+                    errors.push(InstructionError::NameInUse {
+                        v: name.clone(),
+                        instruction,
+                    });
+                } else {
+                    signed.insert(name.clone());
+                }
+            }
+            // Operands that must all be signed or all be unsigned
+            let operands = match &i {
+                Copy { a, b }
+                | Add { a, b }
+                | Sub { a, b }
+                | Mul { a, b }
+                | IfEqual { a, b }
+                | IfNotEqual { a, b }
+                | UntilEqual { a, b }
+                | IfCompare { a, b, .. }
+                | WhileCompare { a, b, .. }
+                | Compare { a, b, .. } => vec![a, b],
+                Div {
+                    a,
+                    b,
+                    remainder,
+                    quotient,
+                } => vec![a, b, remainder, quotient],
+                _ => vec![],
+            };
+            if let Some(v) = operands.iter().find(|v| signed.contains(**v)) {
+                if let Some(other) = operands.iter().find(|v| !signed.contains(**v)) {
+                    errors.push(InstructionError::MixedSignedness {
+                        v: (*v).clone(),
+                        other: (*other).clone(),
+                        instruction,
+                    });
+                }
+            }
+            let narrow = match &i {
+                Push(a) | Pop(a) | Match(a, _) | Not(a) | Shl(a, _) | Shr(a, _) => vec![a],
+                LogicalNot(a) | Bool(a) => vec![a],
+                And { a, b } | Or { a, b } | Xor { a, b } => vec![a, b],
+                LogicalAnd { a, b } | LogicalOr { a, b } => vec![a, b],
+                Load { idx, dst: a, .. } | Store { idx, src: a, .. } => vec![idx, a],
+                _ => vec![],
+            };
+            for v in narrow.into_iter().filter(|v| widths.contains_key(*v)) {
+                errors.push(InstructionError::WideVariable {
+                    v: v.clone(),
+                    instruction,
+                });
             }
-            if !v
-                .chars()
-                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
-            {
-                return Err(InstructionError::InvalidVariableName { v });
+            if let Some(arr) = i.array() {
+                let declared = arrays.contains_key(arr);
+                match &i {
+                    Array { .. } if declared || variables.contains_key(arr) => {
+                        errors.push(InstructionError::NameInUse {
+                            v: arr.clone(),
+                            instruction,
+                        })
+                    }
+                    Array { .. } if !valid_name(arr) => {
+                        errors.push(InstructionError::InvalidVariableName {
+                            v: arr.clone(),
+                            instruction,
+                        })
+                    }
+                    Array { len: 0, .. } => {
+                        errors.push(InstructionError::InvalidArrayLength { instruction })
+                    }
+                    Array { name, len } => {
+                        Self::register_array(&mut variables, &mut index, &mut arrays, name, *len)
+                    }
+                    _ if !declared => errors.push(InstructionError::UnknownArray {
+                        v: arr.clone(),
+                        instruction,
+                    }),
+                    _ => {}
+                }
             }
 
-            variables.insert(v, index);
-            index += 1;
-            Ok(())
-        };
+            let mut var = |v: Variable, must_be_defined: bool| {
+                if arrays.contains_key(&v) {
+                    errors.push(InstructionError::NameInUse { v, instruction });
+                    return;
+                }
+                let result =
+                    Self::register(&mut variables, &mut index, v, must_be_defined, instruction);
+                errors.extend(result.err());
+            };
 
-        // Register and check validity of variable accesses
-        for i in input {
             match i {
                 Copy { a, b } => {
-                    var(a, true)?;
-                    var(b, false)?
-                }
-                Inc(a) => var(a, false)?,
-                Dec(a) => var(a, false)?,
-                IncBy(a, ..) => var(a, false)?,
-                DecBy(a, ..) => var(a, false)?,
-                Set(a, ..) => var(a, false)?,
-                Read(a) => var(a, false)?,
-                Print(a) => var(a, true)?,
-                PrintC(a) => var(a, true)?,
+                    var(a, true);
+                    var(b, false)
+                }
+                Else => Self::register_hidden(&mut variables, &mut index, else_flag(depth)),
+                End => depth = depth.saturating_sub(1),
+                Inc(a) => var(a, false),
+                Dec(a) => var(a, false),
+                IncBy(a, ..) => var(a, false),
+                DecBy(a, ..) => var(a, false),
+                Set(a, ..) => var(a, false),
+                Read(a) => var(a, false),
+                ReadEof { a, eof } => {
+                    var(a, false);
+                    var(eof, false)
+                }
+                ReadN { a, flag } => {
+                    var(a, false);
+                    if let Some(flag) = flag {
+                        var(flag, false)
+                    }
+                }
+                Print(a) => var(a, true),
+                PrintC(a) => var(a, true),
+                PrintCPadded { a, .. } => var(a, true),
+                PrintX(a) => var(a, true),
+                PrintB(a) => var(a, true),
                 Add { a, b } => {
-                    var(a, true)?;
-                    var(b, true)?
+                    var(a, true);
+                    var(b, true)
                 }
                 Sub { a, b } => {
-                    var(a, true)?;
-                    var(b, true)?
+                    var(a, true);
+                    var(b, true)
                 }
                 IfEqual { a, b } => {
-                    var(a, true)?;
-                    var(b, true)?
+                    var(a, true);
+                    var(b, true)
                 }
                 IfEqualConst { a, .. } => {
-                    var(a, true)?;
+                    var(a, true);
                 }
                 IfNotEqual { a, b } => {
-                    var(a, true)?;
-                    var(b, true)?
+                    var(a, true);
+                    var(b, true)
                 }
                 IfNotEqualConst { a, .. } => {
-                    var(a, true)?;
+                    var(a, true);
                 }
                 UntilEqual { a, b } => {
-                    var(a, true)?;
-                    var(b, true)?
+                    var(a, true);
+                    var(b, true)
+                }
+                WhileNotZero(a) | IfNotZero(a) | IfZero(a) => var(a, true),
+                IfCompare { a, b, .. } | WhileCompare { a, b, .. } => {
+                    var(a, true);
+                    var(b, true)
                 }
-                WhileNotZero(a) => var(a, true)?,
-                End => {}
+                IfCompareConst { a, .. } | WhileCompareConst { a, .. } => var(a, true),
                 Raw(_) => {}
                 Compare { a, b, res } => {
-                    var(a, true)?;
-                    var(b, true)?;
-                    var(res, false)?
+                    var(a, true);
+                    var(b, true);
+                    var(res, false)
                 }
                 PrintS(_) => {}
-                Mul { a, b } => {
-                    var(a, true)?;
-                    var(b, true)?
+                Mul { a, b } | And { a, b } | Or { a, b } | Xor { a, b } => {
+                    var(a, true);
+                    var(b, true)
                 }
+                LogicalAnd { a, b } | LogicalOr { a, b } => {
+                    var(a, true);
+                    var(b, true)
+                }
+                Not(a) | Shl(a, _) | Shr(a, _) => var(a, true),
+                LogicalNot(a) | Bool(a) => var(a, true),
                 Div {
                     a,
                     b,
                     remainder: r,
                     quotient: q,
                 } => {
-                    var(a, true)?;
-                    var(b, true)?;
-                    var(r, false)?;
-                    var(q, false)?;
+                    var(a, true);
+                    var(b, true);
+                    var(r, false);
+                    var(q, false);
+                }
+                Signed(a) => var(a, false),
+                Cast { a, b } => {
+                    var(a, true);
+                    var(b, false)
+                }
+                Wide { .. } | Array { .. } => {}
+                Load { idx, dst, .. } => {
+                    var(idx, true);
+                    var(dst, false)
+                }
+                Store { idx, src, .. } => {
+                    var(idx, true);
+                    var(src, true)
                 }
                 Push(a) => {
-                    var(a, true)?;
+                    var(a, true);
                 }
                 Pop(a) => {
-                    var(a, false)?;
+                    var(a, false);
                 }
-                Match(a, _) => var(a, true)?,
+                Match(a, _) => var(a, true),
                 Case() => {}
+                Break(_) | Continue(_) => {}
             }
         }
 
@@ -147,30 +382,210 @@ impl InstructionsAnalysis {
         for i in 0..17 {
             variables.insert(i.to_string(), -i - 1);
         }
+        variables.insert(TRAP.to_string(), TRAP_CELL as i32);
+
+        if !signed.is_empty() {
+            Self::register_hidden(&mut variables, &mut index, SIGN.to_string());
+        }
+        if !widths.is_empty() {
+            // One cell more than the widest variable, for the remainder of a division
+            Self::register_wide(&mut variables, &mut index, &mut widths, WIDE_A, 5);
+            Self::register_wide(&mut variables, &mut index, &mut widths, WIDE_B, 4);
+            for scratch in [WIDE_FLAG, WIDE_DIGIT, WIDE_ZERO] {
+                Self::register_hidden(&mut variables, &mut index, scratch.to_string());
+            }
+        }
+
+        self.variables = variables;
+        self.variable_count = index;
+        self.arrays = arrays;
+        self.widths = widths;
+        self.signed = signed;
+    }
 
-        Ok((variables, index))
+    /// Register a variable `bytes` cells wide, see [`byte_names`]
+    fn register_wide(
+        variables: &mut HashMap<String, i32>,
+        index: &mut i32,
+        widths: &mut HashMap<String, u8>,
+        name: &str,
+        bytes: u8,
+    ) {
+        for byte in byte_names(name, bytes) {
+            variables.insert(byte, *index);
+            *index += 1;
+        }
+        widths.insert(name.to_string(), bytes);
     }
 
-    fn sanity_check(instructions: Vec<Instruction>) -> Result<()> {
-        let mut nesting = 0i32;
-        for i in instructions {
+    /// Register an array, see [`array_cursor`] for its layout
+    fn register_array(
+        variables: &mut HashMap<String, i32>,
+        index: &mut i32,
+        arrays: &mut Arrays,
+        name: &Variable,
+        len: Immediate,
+    ) {
+        let (counter, value) = array_cursor(name);
+        variables.insert(counter, *index + 3);
+        variables.insert(value, *index + 4);
+        arrays.insert(name.clone(), (*index, len));
+        *index += 3 * (len as i32 + 1);
+    }
+
+    /// Register a variable the code generator uses, which isn't checked like a user variable as
+    /// its name is invalid on purpose
+    fn register_hidden(variables: &mut HashMap<String, i32>, index: &mut i32, v: Variable) {
+        variables.entry(v).or_insert_with(|| {
+            *index += 1;
+            *index - 1
+        });
+    }
+
+    /// Register a variable used by the instruction at index `instruction`
+    fn register(
+        variables: &mut HashMap<String, i32>,
+        index: &mut i32,
+        v: Variable,
+        must_be_defined: bool,
+        instruction: usize,
+    ) -> Result<(), InstructionError> {
+        if must_be_defined && !variables.contains_key(&v) {
+            return Err(InstructionError::VariableMustBeAssigned { v, instruction });
+        }
+
+        if variables.contains_key(&v) {
+            return Ok(());
+        }
+
+        if !valid_name(&v) {
+            return Err(InstructionError::InvalidVariableName { v, instruction });
+        }
+
+        variables.insert(v, *index);
+        *index += 1;
+        Ok(())
+    }
+
+    /// Check that blocks are ended, `case` is only used in `match`, `else` only in if blocks and
+    /// `break` and `continue` only in loops
+    ///
+    /// Returns the indices of the loops `break` and `continue` jump out of, including the loops
+    /// they leave on the way.
+    fn sanity_check(
+        instructions: Vec<Instruction>,
+        errors: &mut Vec<InstructionError>,
+    ) -> HashSet<usize> {
+        let mut jump_targets = HashSet::new();
+        // Index and instruction of the instructions that opened the blocks that are still open,
+        // `else` replaces the if it belongs to
+        let mut open: Vec<(usize, Instruction)> = Vec::new();
+        for (instruction, i) in instructions.into_iter().enumerate() {
+            if let Match(_, cases) = &i {
+                if !cases.is_sorted() {
+                    errors.push(InstructionError::UnsortedMatch { instruction });
+                }
+            }
             match i {
-                IfEqual { .. }
-                | IfNotEqual { .. }
-                | UntilEqual { .. }
-                | WhileNotZero(..)
-                | IfNotEqualConst { .. }
-                | IfEqualConst { .. }
-                | Match(..) => nesting += 1,
-                End => nesting -= 1,
+                _ if i.opens_block() => open.push((instruction, i)),
+                Else => match open.last_mut() {
+                    Some((_, block)) if block.is_if() => *block = Else,
+                    _ => errors.push(InstructionError::ElseOutsideIf { instruction }),
+                },
+                End => {
+                    let closed = open.pop();
+                    if closed.is_none() {
+                        errors.push(InstructionError::UnmatchedEnd { instruction });
+                    }
+                }
+                Break(depth) | Continue(depth) => {
+                    let loops: Vec<usize> = open
+                        .iter()
+                        .rev()
+                        .filter(|(_, block)| block.is_loop())
+                        .map(|&(index, _)| index)
+                        .take(depth as usize)
+                        .collect();
+                    if depth == 0 || loops.len() < depth as usize {
+                        errors.push(InstructionError::JumpOutsideLoop { instruction });
+                    } else {
+                        jump_targets.extend(loops);
+                    }
+                }
+                Case()
+                    if !open
+                        .last()
+                        .is_some_and(|(_, block)| matches!(block, Match(..))) =>
+                {
+                    errors.push(InstructionError::CaseOutsideMatch { instruction });
+                }
                 _ => {}
             }
         }
 
-        if nesting != 0 {
-            return Err(InstructionError::UnevenAmountOfBlocks().into());
-        }
+        errors.extend(
+            open.into_iter()
+                .map(|(instruction, _)| InstructionError::UnclosedBlock { instruction }),
+        );
 
-        Ok(())
+        jump_targets
     }
 }
+
+/// Name of the hidden variable `else` uses as a flag when it is `depth` blocks deep
+///
+/// The name can't be written in the source, so it never clashes with a user variable.
+pub fn else_flag(depth: usize) -> Variable {
+    format!("else.{depth}")
+}
+
+/// Name of the hidden variable a loop `depth` blocks deep keeps its state in when `break` or
+/// `continue` jump out of it
+pub fn loop_flag(depth: usize) -> Variable {
+    format!("loop.{depth}")
+}
+
+/// Name of the hidden variable at [`TRAP_CELL`]
+pub const TRAP: &str = "trap.";
+
+/// Names of the hidden variables at the start of array `name`
+///
+/// An array of length `n` takes `3 * n + 3` cells. The first cell is always zero and the rest are
+/// a counter, a value and an element for every element. To access an element, the counter of the
+/// first element is set to the index and walks right, leaving 1 in the counters it passes, and
+/// the value is carried along with it. Going back then stops at the zero cell. Returns the names
+/// of the first counter and the first value.
+pub fn array_cursor(name: &str) -> (Variable, Variable) {
+    (format!("{name}.index"), format!("{name}.value"))
+}
+
+/// Variables must start with a letter and can contain alpha, _-, and digits
+fn valid_name(v: &str) -> bool {
+    v.chars().next().is_some_and(char::is_alphabetic)
+        && v.chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Names of the cells of a variable `bytes` cells wide, lowest first
+///
+/// The lowest cell is the variable itself, the others are hidden variables.
+pub fn byte_names(name: &str, bytes: u8) -> Vec<Variable> {
+    std::iter::once(name.to_string())
+        .chain((1..bytes).map(|byte| format!("{name}.{byte}")))
+        .collect()
+}
+
+/// Hidden flag for whether a signed variable `printc` prints is negative, which only exists if
+/// there are any signed variables
+pub const SIGN: &str = "sign.";
+
+/// Hidden variables five and four cells wide that instructions on wide variables use for
+/// intermediate values, which only exist if there are any wide variables
+pub const WIDE_A: &str = "wide.a";
+pub const WIDE_B: &str = "wide.b";
+
+/// Hidden variables one cell wide that instructions on wide variables use, a loop flag, a digit
+/// being printed and a cell that is always zero
+pub const WIDE_FLAG: &str = "wide.flag";
+pub const WIDE_DIGIT: &str = "wide.digit";
+pub const WIDE_ZERO: &str = "wide.zero";
@@ -1,7 +1,39 @@
-use crate::lir::analysis::InstructionsAnalysis;
-use crate::lir::instruction::{Immediate, Instruction, Instruction::*, Variable};
+use crate::bf::interpreter::EofPolicy;
+use crate::lir::analysis::{
+    array_cursor, byte_names, else_flag, loop_flag, InstructionsAnalysis, SIGN, TRAP, WIDE_A,
+    WIDE_B, WIDE_DIGIT, WIDE_FLAG, WIDE_ZERO,
+};
+use crate::lir::instruction::{
+    Comparison, Constant, Immediate, Instruction, Instruction::*, Variable,
+};
+use crate::pipeline::OptLevel;
 use anyhow::Result;
+use std::collections::HashSet;
+use std::ops::Range;
 use std::string::ToString;
+use thiserror::Error;
+
+/// An error in the instruction at index `instruction`
+#[derive(Debug, Clone, Error)]
+pub enum CodegenError {
+    #[error("Unable to retrieve position of variable {v}")]
+    UnknownVariable { v: Variable, instruction: usize },
+}
+
+impl CodegenError {
+    pub fn instruction(&self) -> usize {
+        match self {
+            CodegenError::UnknownVariable { instruction, .. } => *instruction,
+        }
+    }
+
+    /// The variable the error is about
+    pub fn variable(&self) -> Option<&str> {
+        match self {
+            CodegenError::UnknownVariable { v, .. } => Some(v),
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 enum BlockStack {
@@ -29,6 +61,59 @@ enum BlockStack {
         /// Flag to indicate that the code is in the default case
         is_default_case: bool,
     },
+    Else,
+    IfCompare,
+    WhileCompare {
+        op: Comparison,
+        a: Variable,
+        b: Variable,
+    },
+    WhileCompareConst {
+        op: Comparison,
+        a: Variable,
+        b: Constant,
+    },
+    /// Loop that `break` or `continue` jump out of
+    ///
+    /// `state` is 1 while running, 0 after `break` and 2 after `continue`.
+    Loop {
+        state: Variable,
+        condition: Condition,
+    },
+    /// Skips the rest of a block after `break` or `continue`, see [`Codegen::jump`]
+    Guard,
+}
+
+/// What [`Codegen::comparison`] tests, the orderings of [`Comparison`] and the equality tests
+/// `if_eq` and `if_neq` need on wide variables
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Relation {
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+impl From<Comparison> for Relation {
+    fn from(op: Comparison) -> Self {
+        match op {
+            Comparison::Less => Relation::Less,
+            Comparison::Greater => Relation::Greater,
+            Comparison::LessEqual => Relation::LessEqual,
+            Comparison::GreaterEqual => Relation::GreaterEqual,
+        }
+    }
+}
+
+/// Condition of a [`BlockStack::Loop`]
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    NotZero(Variable),
+    NotEqual(Variable, Variable),
+    Compare(Comparison, Variable, Variable),
+    CompareConst(Comparison, Variable, Constant),
 }
 
 #[derive(Debug, Clone)]
@@ -38,7 +123,17 @@ pub struct Codegen {
     pub instructions: Vec<Instruction>,
     parsed: InstructionsAnalysis,
     block_stack: Vec<BlockStack>,
+    /// Indices in `block_stack` of blocks that `break` or `continue` jumped out of
+    escaped: HashSet<usize>,
     instruction_separator: String,
+    /// Range of generated code for every instruction, not including the separator
+    ranges: Vec<Range<usize>>,
+    /// Decides between short and fast code where they differ
+    level: OptLevel,
+    /// What `,` does at the end of input where the code will run
+    eof: EofPolicy,
+    /// Whether array indices are checked, see [`Codegen::with_sanitize`]
+    sanitize: bool,
 }
 
 impl Codegen {
@@ -49,7 +144,12 @@ impl Codegen {
             instructions,
             parsed: InstructionsAnalysis::default(),
             block_stack: Vec::new(),
+            escaped: HashSet::new(),
             instruction_separator: String::from("#"),
+            ranges: Vec::new(),
+            level: OptLevel::O0,
+            eof: EofPolicy::Zero,
+            sanitize: false,
         }
     }
 
@@ -62,56 +162,235 @@ impl Codegen {
             instructions,
             parsed: InstructionsAnalysis::default(),
             block_stack: Vec::new(),
+            escaped: HashSet::new(),
             instruction_separator: String::from("#"),
+            ranges: Vec::new(),
+            level: OptLevel::O0,
+            eof: EofPolicy::Zero,
+            sanitize: false,
         }
     }
 
-    pub fn codegen(mut self) -> Result<String> {
+    /// Generate code for `level`, [`OptLevel::O0`] by default
+    pub fn with_opt_level(mut self, level: OptLevel) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Generate code for an interpreter with `eof`, [`EofPolicy::Zero`] by default
+    pub fn with_eof(mut self, eof: EofPolicy) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    /// Check that array indices are in range, `load` and `store` set [`TRAP`] instead of
+    /// accessing the array when they are not, which the interpreter reports at the next `#`
+    pub fn with_sanitize(mut self, sanitize: bool) -> Self {
+        self.sanitize = sanitize;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn codegen(self) -> Result<String> {
+        Ok(self.codegen_with_map()?.0)
+    }
+
+    /// Generate code, also returning the range of code each instruction generated
+    pub fn codegen_with_map(mut self) -> Result<(String, Vec<Range<usize>>)> {
         self.parsed = InstructionsAnalysis::new(self.instructions.clone())?;
 
         for instruction in self.instructions.clone() {
             self.instruction(instruction)?
         }
 
-        Ok(self.code)
+        Ok((self.code, self.ranges))
     }
 
     fn instruction(&mut self, instruction: Instruction) -> Result<()> {
+        let start = self.code.len();
+
+        if let Some(v) = instruction
+            .variables()
+            .into_iter()
+            .find(|v| !self.parsed.variables.contains_key(*v))
+        {
+            return Err(CodegenError::UnknownVariable {
+                v: v.clone(),
+                instruction: self.ranges.len(),
+            }
+            .into());
+        }
+
+        let jump_target = self.parsed.flag_loops.contains(&self.ranges.len());
+        let wide = instruction.variables().iter().any(|v| self.is_wide(v));
         match instruction {
+            WhileNotZero(a) if jump_target => self.jump_loop(Condition::NotZero(a)),
+            UntilEqual { a, b } if jump_target => self.jump_loop(Condition::NotEqual(a, b)),
+            WhileCompare { op, a, b } if jump_target => {
+                self.jump_loop(Condition::Compare(op, a, b))
+            }
+            WhileCompareConst { op, a, b } if jump_target => {
+                self.jump_loop(Condition::CompareConst(op, a, b))
+            }
+            Wide { name, .. } => self.zero_wide(&name),
+            Signed(a) => self.zero(&a),
+            Cast { a, b } => self.cast(&a, &b),
+            Copy { a, b } if wide => self.copy_wide(&a, &b),
             Copy { a, b } => self.copy(&a, &b),
+            Inc(a) if wide => self.inc_by_bytes(&self.bytes(&a), 1),
+            Dec(a) if wide => self.dec_by_bytes(&self.bytes(&a), 1),
+            IncBy(a, b) if wide => self.inc_by_bytes(&self.bytes(&a), b),
+            DecBy(a, b) if wide => self.dec_by_bytes(&self.bytes(&a), b),
+            Set(a, b) if wide => self.set_bytes(&self.bytes(&a), b),
             Inc(a) => self.inc_by(&a, &1),
             Dec(a) => self.dec_by(&a, &1),
-            IncBy(a, b) => self.inc_by(&a, &b),
-            DecBy(a, b) => self.dec_by(&a, &b),
-            Set(a, b) => self.set(&a, &b),
-            Read(a) => self.read(&a),
+            IncBy(a, b) => self.inc_by(&a, &(b as Immediate)),
+            DecBy(a, b) => self.dec_by(&a, &(b as Immediate)),
+            Set(a, b) => self.set(&a, &(b as Immediate)),
+            Read(a) => {
+                self.zero_upper(&a);
+                self.read(&a)
+            }
+            ReadEof { a, eof } => {
+                self.zero_upper(&a);
+                self.zero_upper(&eof);
+                self.read_eof(&a, &eof)
+            }
+            ReadN { a, flag } => {
+                if let Some(flag) = &flag {
+                    self.zero_upper(flag);
+                }
+                self.readn(&a, flag.as_ref())
+            }
             Print(a) => self.print(&a),
-            PrintC(a) => self.printc(&a),
+            PrintC(a) if self.is_signed(&a) => self.printc_signed(&a, 0, b' '),
+            PrintCPadded { a, width, pad } if self.is_signed(&a) => {
+                self.printc_signed(&a, width, pad)
+            }
+            PrintC(a) if wide => self.printc_wide(&a, 0, b' '),
+            PrintCPadded { a, width, pad } if wide => self.printc_wide(&a, width, pad),
+            PrintC(a) => self.printc(&a, 0, b' '),
+            PrintCPadded { a, width, pad } => self.printc(&a, width, pad),
+            // Highest cell first
+            PrintX(a) => {
+                for byte in self.bytes(&a).iter().rev() {
+                    self.printx(byte);
+                }
+            }
+            PrintB(a) => {
+                for byte in self.bytes(&a).iter().rev() {
+                    self.printb(byte);
+                }
+            }
+            Add { a, b } if wide => self.add_wide(&a, &b),
+            Sub { a, b } if wide => self.sub_wide(&a, &b),
             Add { a, b } => self.add(&a, &b),
             Sub { a, b } => self.sub(&a, &b),
             Raw(raw) => self.code += &*raw,
+            IfEqual { a, b } if wide => {
+                self.comparison(Relation::Equal, &a, &b);
+                self.compare_block(BlockStack::IfCompare);
+            }
+            IfNotEqual { a, b } if wide => {
+                self.comparison(Relation::NotEqual, &a, &b);
+                self.compare_block(BlockStack::IfCompare);
+            }
+            IfEqualConst { a, b } if wide => {
+                self.comparison_const(Relation::Equal, &a, b);
+                self.compare_block(BlockStack::IfCompare);
+            }
+            IfNotEqualConst { a, b } if wide => {
+                self.comparison_const(Relation::NotEqual, &a, b);
+                self.compare_block(BlockStack::IfCompare);
+            }
+            IfNotZero(a) if wide => {
+                self.comparison_const(Relation::NotEqual, &a, 0);
+                self.compare_block(BlockStack::IfCompare);
+            }
+            IfZero(a) if wide => {
+                self.comparison_const(Relation::Equal, &a, 0);
+                self.compare_block(BlockStack::IfCompare);
+            }
+            IfNotZero(a) => self.if_not_zero(&a),
+            IfZero(a) => self.if_zero(&a),
             IfEqual { a, b } => self.if_equal(&a, &b),
-            IfEqualConst { a, b } => self.if_equal_const(&a, &b),
+            IfEqualConst { a, b } => self.if_equal_const(&a, &(b as Immediate)),
             IfNotEqual { a, b } => self.if_not_equal(&a, &b),
-            IfNotEqualConst { a, b } => self.if_not_equal_const(&a, &b),
+            IfNotEqualConst { a, b } => self.if_not_equal_const(&a, &(b as Immediate)),
             UntilEqual { a, b } => self.until_equal(&a, &b),
             WhileNotZero(a) => self.while_not_zero(&a),
-            End => self.end(),
+            IfCompare { op, a, b } => {
+                self.comparison(op.into(), &a, &b);
+                self.compare_block(BlockStack::IfCompare);
+            }
+            IfCompareConst { op, a, b } => {
+                self.comparison_const(op.into(), &a, b);
+                self.compare_block(BlockStack::IfCompare);
+            }
+            WhileCompare { op, a, b } => {
+                self.comparison(op.into(), &a, &b);
+                self.compare_block(BlockStack::WhileCompare { op, a, b });
+            }
+            WhileCompareConst { op, a, b } => {
+                self.comparison_const(op.into(), &a, b);
+                self.compare_block(BlockStack::WhileCompareConst { op, a, b });
+            }
+            Else => self.else_block(),
+            Break(depth) => self.jump(depth as usize, false),
+            Continue(depth) => self.jump(depth as usize, true),
+            End => self.end_block(),
+            Compare { a, b, res } if self.is_signed(&a) => {
+                self.zero_upper(&res);
+                self.flip_signs(&a, &b);
+                self.compare(&a, &b, &res);
+                self.flip_signs(&a, &b);
+            }
+            Compare { a, b, res } if wide => {
+                self.zero_upper(&res);
+                self.compare_bytes(&self.bytes(&a), &self.bytes(&b), &res);
+            }
             Compare { a, b, res } => self.compare(&a, &b, &res),
             PrintS(msg) => self.prints(msg),
+            Mul { a, b } if wide => self.mul_wide(&a, &b),
+            Div {
+                a,
+                b,
+                remainder: r,
+                quotient: q,
+            } if self.is_signed(&a) => self.div_signed(&a, &b, &r, &q),
+            Div {
+                a,
+                b,
+                remainder: r,
+                quotient: q,
+            } if wide => self.div_wide(&a, &b, &r, &q),
             Mul { a, b } => self.mul(&a, &b),
+            And { a, b } => self.bitwise(&a, &b, &[2]),
+            Or { a, b } => self.bitwise(&a, &b, &[1, 2]),
+            Xor { a, b } => self.bitwise(&a, &b, &[1]),
+            Not(a) => self.not(&a),
+            LogicalAnd { a, b } => self.logical_and(&a, &b),
+            LogicalOr { a, b } => self.logical_or(&a, &b),
+            LogicalNot(a) => self.logical_not(&a),
+            Bool(a) => self.normalize(&a),
+            Shl(a, bits) => self.shl(&a, bits),
+            Shr(a, bits) => self.shr(&a, bits),
             Div {
                 a,
                 b,
                 remainder: r,
                 quotient: q,
             } => self.div(&a, &b, &r, &q),
+            Array { .. } => {}
+            Load { arr, idx, dst } => self.load(&arr, &idx, &dst),
+            Store { arr, idx, src } => self.store(&arr, &idx, &src),
             Push(a) => self.stack_push(&a),
             Pop(a) => self.stack_pop(&a),
             Match(a, cases) => self.match_var(&a, cases),
             Case() => self.case(),
         }
 
+        self.ranges.push(start..self.code.len());
         self.code += &self.instruction_separator;
 
         Ok(())
@@ -133,10 +412,9 @@ impl Codegen {
 
                  */
 
-        // Make sure cases are sorted
-        let cases: Vec<u8> = cases.to_vec();
-        assert!(cases.is_sorted());
-        assert!(!cases.is_empty());
+        // Analysis rejects unsorted cases and the grammar needs at least one
+        debug_assert!(cases.is_sorted());
+        debug_assert!(!cases.is_empty());
 
         // Copy to 1 and use 0 as temp so that the output matches the tutorial's example
         self.copy(a, &"1".to_string());
@@ -162,6 +440,8 @@ impl Codegen {
     }
 
     fn case(&mut self) {
+        self.close_guards();
+
         // If the code is after the default case
         if let Some(BlockStack::Match { is_default_case }) = self.block_stack.last_mut() {
             if *is_default_case {
@@ -245,6 +525,145 @@ impl Codegen {
         self.move_value(&"3".to_string(), a);
     }
 
+    /// Logical and of a boolean and any variable
+    ///
+    /// Uses temporary variables `2` and `3`
+    fn logical_and(&mut self, a: &Variable, b: &Variable) {
+        self.if_zero(b);
+        self.zero(a);
+        self.end();
+    }
+
+    /// Logical or of a boolean and any variable
+    ///
+    /// Uses temporary variable `2`
+    fn logical_or(&mut self, a: &Variable, b: &Variable) {
+        self.if_not_zero(b);
+        self.set(a, &1);
+        self.end();
+    }
+
+    /// Logical not of a boolean, which is `1 - a`
+    ///
+    /// Uses temporary variable `0`
+    fn logical_not(&mut self, a: &Variable) {
+        self.move_value(a, &"0".to_string());
+        self.set(a, &1);
+        self.while_not_zero(&"0".to_string());
+        self.dec_by(&"0".to_string(), &1);
+        self.dec_by(a, &1);
+        self.end();
+        self.goto(a);
+    }
+
+    /// Set a variable to 1 if it is not zero
+    ///
+    /// Uses temporary variable `0`
+    fn normalize(&mut self, a: &Variable) {
+        self.goto(a);
+        self.code += "[";
+        self.zero(a);
+        self.set(&"0".to_string(), &1);
+        self.goto(a);
+        self.code += "]";
+        self.move_value(&"0".to_string(), a);
+    }
+
+    /// Halve a variable, setting `bit` to the remainder
+    ///
+    /// `bit` must be zero. Uses temporary variables `2`, `3` and `14`
+    fn halve(&mut self, a: &Variable, bit: &Variable) {
+        let half = "14".to_string();
+
+        self.while_not_zero(a);
+        self.dec_by(a, &1);
+        self.inc_by(bit, &1);
+        self.if_equal_const(bit, &2);
+        self.zero(bit);
+        self.inc_by(&half, &1);
+        self.end();
+        self.end();
+
+        self.move_value(&half, a);
+    }
+
+    /// Set every bit of `a` to whether the number of bits set at its place in `a` and `b` is one
+    /// of `counts`, which makes `and`, `or` and `xor`
+    ///
+    /// The bits are taken from the lowest up by halving copies of the variables in temp10 and
+    /// temp11. Uses temporary variables `0`, `2`, `3`, `10`, `11`, `12`, `13` and `14`
+    fn bitwise(&mut self, a: &Variable, b: &Variable, counts: &[Immediate]) {
+        let (x, y) = ("10".to_string(), "11".to_string());
+        let (bit_x, bit_y) = ("12".to_string(), "13".to_string());
+        self.copy(a, &x);
+        self.copy(b, &y);
+        self.zero(a);
+
+        for place in 0..8 {
+            self.halve(&x, &bit_x);
+            self.halve(&y, &bit_y);
+
+            // Temp12 is the number of bits set
+            self.add(&bit_x, &bit_y);
+            self.zero(&bit_y);
+            for count in counts {
+                self.if_equal_const(&bit_x, count);
+                self.inc_by(a, &(1 << place));
+                self.end();
+            }
+            self.zero(&bit_x);
+        }
+
+        // Temp10 and temp11 are zero after halving them eight times
+        self.goto(a);
+    }
+
+    /// Flip every bit of a variable, which is `255 - a`
+    ///
+    /// Uses temporary variable `0`
+    fn not(&mut self, a: &Variable) {
+        self.move_value(a, &"0".to_string());
+        self.set(a, &255);
+        self.while_not_zero(&"0".to_string());
+        self.dec_by(&"0".to_string(), &1);
+        self.dec_by(a, &1);
+        self.end();
+        self.goto(a);
+    }
+
+    /// Shift a variable left by `bits`, multiplying it by `2^bits`
+    ///
+    /// Uses temporary variable `0`
+    fn shl(&mut self, a: &Variable, bits: Immediate) {
+        match bits {
+            0 => {}
+            8.. => self.zero(a),
+            _ => {
+                self.move_value(a, &"0".to_string());
+                self.while_not_zero(&"0".to_string());
+                self.dec_by(&"0".to_string(), &1);
+                self.inc_by(a, &(1 << bits));
+                self.end();
+                self.goto(a);
+            }
+        }
+    }
+
+    /// Shift a variable right by `bits`, halving it `bits` times
+    ///
+    /// Uses temporary variables `2`, `3`, `12` and `14`
+    fn shr(&mut self, a: &Variable, bits: Immediate) {
+        if bits >= 8 {
+            self.zero(a);
+            return;
+        }
+        let bit = "12".to_string();
+        for _ in 0..bits {
+            self.halve(a, &bit);
+            self.zero(&bit);
+        }
+    }
+
     /// Compare two variables and store the result in a third variable
     ///
     /// Uses temporary variables `3`, `4` and `5`
@@ -258,6 +677,17 @@ impl Codegen {
         self.set(&"1".to_string(), &0);
         self.set(&"0".to_string(), &1);
 
+        // The loop below only sees a variable reach zero after decrementing it
+        self.if_equal_const(a, &0);
+        self.set(res, &1);
+        self.set(&"0".to_string(), &0);
+        self.end();
+
+        self.if_equal_const(b, &0);
+        self.set(res, &2);
+        self.set(&"0".to_string(), &0);
+        self.end();
+
         self.while_not_zero(&"0".to_string());
 
         self.inc_by(&"1".to_string(), &1);
@@ -435,6 +865,275 @@ impl Codegen {
         self.block_stack.push(BlockStack::WhileNotZero(a.clone()));
     }
 
+    /// If a variable is not zero, execute the code
+    ///
+    /// Moves `a` to temp2 and back once the block runs, which is cheaper than the copy
+    /// [`Self::if_not_equal_const`] makes. Uses temporary variable `2`
+    fn if_not_zero(&mut self, a: &Variable) {
+        debug_assert_ne!(a, &"2".to_string());
+
+        self.move_value(a, &"2".to_string());
+        self.goto(&"2".to_string());
+        self.code += "[";
+        // `a` is zero until it is moved back
+        self.while_not_zero(&"2".to_string());
+        self.dec_by(&"2".to_string(), &1);
+        self.inc_by(a, &1);
+        self.end();
+
+        self.block_stack.push(BlockStack::IfCompare);
+    }
+
+    /// If a variable is zero, execute the code
+    ///
+    /// Moves `a` to temp3 and back, clearing the flag in temp2 on the way, which is cheaper than
+    /// [`Self::if_equal_const`]. Uses temporary variables `2` and `3`
+    fn if_zero(&mut self, a: &Variable) {
+        debug_assert_ne!(a, &"2".to_string());
+        debug_assert_ne!(a, &"3".to_string());
+
+        self.set(&"2".to_string(), &1);
+        self.move_value(a, &"3".to_string());
+        self.while_not_zero(&"3".to_string());
+        self.dec_by(&"3".to_string(), &1);
+        self.inc_by(a, &1);
+        self.zero(&"2".to_string());
+        self.end();
+
+        self.compare_block(BlockStack::IfCompare);
+    }
+
+    /// Set temp2 to 1 if `a` is ordered against `b` like `op` says, or to 0
+    ///
+    /// Uses temporary variables `0` to `5`, and `6` if either is wide
+    fn comparison(&mut self, op: Relation, a: &Variable, b: &Variable) {
+        // Temp4 is 0 if equal, 1 if `a` is less and 2 if `a` is greater
+        if self.is_signed(a) {
+            self.flip_signs(a, b);
+            self.compare(a, b, &"4".to_string());
+            self.flip_signs(a, b);
+        } else if self.is_wide(a) || self.is_wide(b) {
+            self.compare_bytes(&self.bytes(a), &self.bytes(b), &"4".to_string());
+        } else {
+            self.compare(a, b, &"4".to_string());
+        }
+        self.select_comparison(op);
+    }
+
+    /// Set temp2 from the result of a compare in temp4 and zero temp4
+    fn select_comparison(&mut self, op: Relation) {
+        match op {
+            Relation::Less => self.if_equal_const(&"4".to_string(), &1),
+            Relation::Greater => self.if_equal_const(&"4".to_string(), &2),
+            Relation::LessEqual => self.if_not_equal_const(&"4".to_string(), &2),
+            Relation::GreaterEqual => self.if_not_equal_const(&"4".to_string(), &1),
+            Relation::Equal => self.if_equal_const(&"4".to_string(), &0),
+            Relation::NotEqual => self.if_not_equal_const(&"4".to_string(), &0),
+        }
+        self.set(&"5".to_string(), &1);
+        self.end();
+
+        self.zero(&"4".to_string());
+        self.move_value(&"5".to_string(), &"2".to_string());
+    }
+
+    /// [`Self::comparison`] against a constant
+    ///
+    /// Uses temporary variables `0` to `6`, and [`WIDE_B`] to hold the constant if `a` is wide
+    fn comparison_const(&mut self, op: Relation, a: &Variable, b: Constant) {
+        if self.is_wide(a) {
+            self.bytes_comparison_const(op, &self.bytes(a), b);
+        } else if self.is_signed(a) {
+            self.inc_by(a, &128);
+            self.byte_comparison_const(op, a, b as Immediate ^ 128);
+            self.inc_by(a, &128);
+        } else {
+            self.byte_comparison_const(op, a, b as Immediate);
+        }
+    }
+
+    /// [`Self::comparison_const`] for the number in the cells `bytes`, lowest first
+    ///
+    /// Uses temporary variables `0` to `6`, and [`WIDE_B`] to hold the constant
+    fn bytes_comparison_const(&mut self, op: Relation, bytes: &[Variable], b: Constant) {
+        let constant = self.bytes(WIDE_B);
+        let constant = &constant[..bytes.len().min(constant.len())];
+        self.set_bytes(constant, b);
+        self.compare_bytes(bytes, constant, &"4".to_string());
+        self.select_comparison(op);
+        for byte in constant {
+            self.zero(byte);
+        }
+    }
+
+    /// [`Self::comparison_const`] for a single cell, even if it is the lowest cell of a wide
+    /// variable
+    ///
+    /// Uses temporary variables `0` to `6`
+    fn byte_comparison_const(&mut self, op: Relation, a: &Variable, b: Immediate) {
+        self.set(&"6".to_string(), &b);
+        self.compare(a, &"6".to_string(), &"4".to_string());
+        self.select_comparison(op);
+        self.zero(&"6".to_string());
+    }
+
+    /// Start `block`, which runs if temp2 is set by [`Self::comparison`]
+    fn compare_block(&mut self, block: BlockStack) {
+        self.goto(&"2".to_string());
+        self.code += "[";
+        self.zero(&"2".to_string());
+
+        self.block_stack.push(block);
+    }
+
+    /// End the then block of an if and start the else block
+    ///
+    /// The then block sets the flag [`else_flag`] of its depth when it runs, the else block runs
+    /// when it is not set and clears it. Uses temporary variable `2`
+    fn else_block(&mut self) {
+        self.close_guards();
+        let flag = else_flag(self.depth());
+
+        self.inc_by(&flag, &1);
+        self.end();
+
+        // Flag temp2 is 1 if the then block did not run
+        self.set(&"2".to_string(), &1);
+        self.while_not_zero(&flag);
+        self.zero(&flag);
+        self.zero(&"2".to_string());
+        self.end();
+
+        self.goto(&"2".to_string());
+        self.code += "[";
+        self.zero(&"2".to_string());
+
+        // The else block takes over the index, so a jump out of the then block still skips
+        // what comes after it
+        self.block_stack.push(BlockStack::Else);
+    }
+
+    /// Start a loop that `break` and `continue` can jump out of
+    ///
+    /// The loop runs on the hidden variable [`loop_flag`], which is set to the condition before
+    /// every iteration unless `break` cleared it.
+    fn jump_loop(&mut self, condition: Condition) {
+        let state = loop_flag(self.depth() + 1);
+        self.condition(&condition, &state);
+        self.goto(&state);
+        self.code += "[";
+
+        self.block_stack.push(BlockStack::Loop { state, condition });
+    }
+
+    /// Set `flag` to 1 if `condition` holds, or to 0
+    ///
+    /// Uses temporary variables `0` to `6`
+    fn condition(&mut self, condition: &Condition, flag: &Variable) {
+        match condition {
+            Condition::NotZero(a) if self.is_wide(a) => {
+                self.comparison_const(Relation::NotEqual, a, 0);
+                self.move_value(&"2".to_string(), flag);
+            }
+            Condition::NotEqual(a, b) if self.is_wide(a) || self.is_wide(b) => {
+                self.comparison(Relation::NotEqual, a, b);
+                self.move_value(&"2".to_string(), flag);
+            }
+            Condition::NotZero(a) => {
+                self.zero(flag);
+                self.if_not_equal_const(a, &0);
+                self.set(flag, &1);
+                self.end();
+            }
+            Condition::NotEqual(a, b) => {
+                self.zero(flag);
+                self.if_not_equal(a, b);
+                self.set(flag, &1);
+                self.end();
+            }
+            Condition::Compare(op, a, b) => {
+                self.comparison((*op).into(), a, b);
+                self.move_value(&"2".to_string(), flag);
+            }
+            Condition::CompareConst(op, a, b) => {
+                self.comparison_const((*op).into(), a, *b);
+                self.move_value(&"2".to_string(), flag);
+            }
+        }
+    }
+
+    /// `break` or `continue` the loop `depth` loops out
+    ///
+    /// Sets the state of the loop, and of the loops it leaves on the way to 0. The rest of every
+    /// block up to the loop only runs if the innermost loop is still running, by starting a
+    /// [`BlockStack::Guard`] after the jump and after every block it is in.
+    fn jump(&mut self, depth: usize, is_continue: bool) {
+        let loops: Vec<(usize, Variable)> = self
+            .block_stack
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(index, block)| match block {
+                BlockStack::Loop { state, .. } => Some((index, state.clone())),
+                _ => None,
+            })
+            .take(depth)
+            .collect();
+
+        for (i, (_, state)) in loops.iter().enumerate() {
+            let value = if is_continue && i + 1 == depth { 2 } else { 0 };
+            self.set(state, &value);
+        }
+
+        let (target, _) = loops[depth - 1];
+        self.escaped.extend(target + 1..self.block_stack.len());
+        self.guard();
+    }
+
+    /// Start a [`BlockStack::Guard`] on the state of the innermost loop
+    ///
+    /// Uses temporary variables `1`, `2` and `3`
+    fn guard(&mut self) {
+        let state = self
+            .block_stack
+            .iter()
+            .rev()
+            .find_map(|block| match block {
+                BlockStack::Loop { state, .. } => Some(state.clone()),
+                _ => None,
+            })
+            .unwrap();
+
+        self.if_equal_const(&state, &1);
+        *self.block_stack.last_mut().unwrap() = BlockStack::Guard;
+    }
+
+    /// Number of open blocks of the source, as counted by the analysis
+    fn depth(&self) -> usize {
+        self.block_stack
+            .iter()
+            .filter(|block| **block != BlockStack::Guard)
+            .count()
+    }
+
+    /// End the guards of the innermost block, they end with it
+    fn close_guards(&mut self) {
+        while self.block_stack.last() == Some(&BlockStack::Guard) {
+            self.escaped.remove(&(self.block_stack.len() - 1));
+            self.end();
+        }
+    }
+
+    /// End a block of the source, skipping the rest of the block around it if a jump left it
+    fn end_block(&mut self) {
+        self.close_guards();
+        let escaped = self.escaped.remove(&(self.block_stack.len() - 1));
+        self.end();
+        if escaped {
+            self.guard();
+        }
+    }
+
     /// End blocks
     fn end(&mut self) {
         let b = self.block_stack.pop().unwrap();
@@ -460,7 +1159,27 @@ impl Codegen {
                 self.goto(&"2".to_string());
                 self.code += "]";
             }
-            BlockStack::IfEqualConst { .. } => {
+            BlockStack::WhileCompare { op, a, b } => {
+                self.comparison(op.into(), &a, &b);
+                self.goto(&"2".to_string());
+                self.code += "]";
+            }
+            BlockStack::WhileCompareConst { op, a, b } => {
+                self.comparison_const(op.into(), &a, b);
+                self.goto(&"2".to_string());
+                self.code += "]";
+            }
+            BlockStack::Loop { state, condition } => {
+                self.if_not_equal_const(&state, &0);
+                self.condition(&condition, &state);
+                self.end();
+                self.goto(&state);
+                self.code += "]";
+            }
+            BlockStack::IfEqualConst { .. }
+            | BlockStack::IfCompare
+            | BlockStack::Else
+            | BlockStack::Guard => {
                 self.zero(&"2".to_string());
                 self.code += "]";
             }
@@ -470,6 +1189,8 @@ impl Codegen {
                 self.zero(&"2".to_string());
             }
             BlockStack::Match { .. } => {
+                // The case loops test temp0, so leave it there in case a case was skipped
+                self.goto(&"0".to_string());
                 self.code += "]";
             }
         }
@@ -560,6 +1281,319 @@ impl Codegen {
         self.goto(to);
     }
 
+    /// Whether `a` was declared with `var16` or `var32`
+    fn is_wide(&self, a: &Variable) -> bool {
+        self.parsed.widths.contains_key(a)
+    }
+
+    /// The cells of `a`, lowest first
+    fn bytes(&self, a: &str) -> Vec<Variable> {
+        byte_names(a, *self.parsed.widths.get(a).unwrap_or(&1))
+    }
+
+    /// Zero every cell of `a`
+    fn zero_wide(&mut self, a: &str) {
+        for byte in self.bytes(a) {
+            self.zero(&byte);
+        }
+    }
+
+    /// Zero the cells of `a` above the lowest, before an instruction writes only the lowest
+    fn zero_upper(&mut self, a: &Variable) {
+        for byte in &self.bytes(a)[1..] {
+            self.zero(byte);
+        }
+    }
+
+    /// Set the cells `bytes`, lowest first, to a constant
+    fn set_bytes(&mut self, bytes: &[Variable], b: Constant) {
+        for (byte, value) in bytes.iter().zip(b.to_le_bytes()) {
+            self.set(byte, &value);
+        }
+    }
+
+    /// Copy variable `from` to `to`, either of which can be wide
+    ///
+    /// Extra cells of `to` are zeroed and extra cells of `from` are dropped. Uses temporary
+    /// variable `0`
+    fn copy_wide(&mut self, from: &str, to: &str) {
+        let from = self.bytes(from);
+        for (i, byte) in self.bytes(to).iter().enumerate() {
+            match from.get(i) {
+                Some(from) => self.copy(from, byte),
+                None => self.zero(byte),
+            }
+        }
+    }
+
+    /// Increment the number in `bytes`, carrying into the cells above the lowest
+    ///
+    /// Uses temporary variables `2` and `3`
+    fn inc_bytes(&mut self, bytes: &[Variable]) {
+        self.inc_by(&bytes[0], &1);
+        if bytes.len() > 1 {
+            self.if_equal_const(&bytes[0], &0);
+            self.inc_bytes(&bytes[1..]);
+            self.end();
+        }
+    }
+
+    /// Decrement the number in `bytes`, borrowing from the cells above the lowest
+    ///
+    /// Uses temporary variables `2` and `3`
+    fn dec_bytes(&mut self, bytes: &[Variable]) {
+        if bytes.len() > 1 {
+            self.if_equal_const(&bytes[0], &0);
+            self.dec_bytes(&bytes[1..]);
+            self.end();
+        }
+        self.dec_by(&bytes[0], &1);
+    }
+
+    /// Increment the number in `bytes` by a constant, one cell at a time
+    ///
+    /// A cell carries when it is at least `256 - c` before adding its byte `c` of the constant.
+    /// Uses temporary variables `0` to `6`
+    fn inc_by_bytes(&mut self, bytes: &[Variable], b: Constant) {
+        for (i, value) in b.to_le_bytes().into_iter().enumerate().take(bytes.len()) {
+            if value == 0 {
+                continue;
+            }
+            if i + 1 < bytes.len() {
+                self.byte_comparison_const(Relation::GreaterEqual, &bytes[i], value.wrapping_neg());
+                self.compare_block(BlockStack::IfCompare);
+                self.inc_bytes(&bytes[i + 1..]);
+                self.end();
+            }
+            self.inc_by(&bytes[i], &value);
+        }
+    }
+
+    /// Decrement the number in `bytes` by a constant, one cell at a time
+    ///
+    /// A cell borrows when it is less than its byte of the constant. Uses temporary variables `0`
+    /// to `6`
+    fn dec_by_bytes(&mut self, bytes: &[Variable], b: Constant) {
+        for (i, value) in b.to_le_bytes().into_iter().enumerate().take(bytes.len()) {
+            if value == 0 {
+                continue;
+            }
+            if i + 1 < bytes.len() {
+                self.byte_comparison_const(Relation::Less, &bytes[i], value);
+                self.compare_block(BlockStack::IfCompare);
+                self.dec_bytes(&bytes[i + 1..]);
+                self.end();
+            }
+            self.dec_by(&bytes[i], &value);
+        }
+    }
+
+    /// Add variable `from` to variable `to`, either of which can be wide
+    ///
+    /// Uses temporary variables `0` to `3` and `7`
+    fn add_wide(&mut self, to: &str, from: &str) {
+        debug_assert_ne!(from, to);
+        let (to, from) = (self.bytes(to), self.bytes(from));
+        self.add_bytes(&to, &from);
+    }
+
+    /// Add the number in the cells `from` to the cells `to`, lowest first
+    ///
+    /// Each cell of `from` is counted down in temp7, incrementing `to` from the same cell up.
+    /// Uses temporary variables `0` to `3` and `7`
+    fn add_bytes(&mut self, to: &[Variable], from: &[Variable]) {
+        let counter = "7".to_string();
+        for (i, byte) in from.iter().enumerate().take(to.len()) {
+            self.copy(byte, &counter);
+            self.while_not_zero(&counter);
+            self.dec_by(&counter, &1);
+            self.inc_bytes(&to[i..]);
+            self.end();
+        }
+    }
+
+    /// Subtract variable `from` from variable `to`, either of which can be wide
+    ///
+    /// Uses temporary variables `0` to `3` and `7`
+    fn sub_wide(&mut self, to: &str, from: &str) {
+        debug_assert_ne!(from, to);
+        let counter = "7".to_string();
+        let to = self.bytes(to);
+        for (i, byte) in self.bytes(from).iter().enumerate().take(to.len()) {
+            self.copy(byte, &counter);
+            self.while_not_zero(&counter);
+            self.dec_by(&counter, &1);
+            self.dec_bytes(&to[i..]);
+            self.end();
+        }
+    }
+
+    /// Multiply two variables, either of which can be wide, into `a`
+    ///
+    /// Long multiplication one cell at a time: `a` is added to the product in [`WIDE_A`] from
+    /// cell `k` up as many times as cell `k` of a copy of `b` in [`WIDE_B`] counts down. Uses
+    /// temporary variables `0` to `3` and `7`
+    fn mul_wide(&mut self, a: &Variable, b: &Variable) {
+        let factor = self.bytes(a);
+        let product = self.bytes(WIDE_A)[..factor.len()].to_vec();
+        let counter = self.bytes(WIDE_B);
+        self.copy_wide(b, WIDE_B);
+
+        for (k, cell) in counter.iter().enumerate().take(factor.len()) {
+            self.while_not_zero(cell);
+            self.dec_by(cell, &1);
+            self.add_bytes(&product[k..], &factor);
+            self.end();
+        }
+        // Cells of the copy that only affect cells above `a`
+        self.zero_wide(WIDE_B);
+
+        for (from, to) in product.iter().zip(&factor) {
+            self.move_value(from, to);
+        }
+        self.goto(a);
+    }
+
+    /// Divide two variables, either of which can be wide
+    ///
+    /// Long division one cell at a time: the remainder in [`WIDE_A`] is shifted up a cell to take
+    /// the next cell of `a`, then `b` is subtracted while it fits, at most 255 times, counting the
+    /// cell of the quotient. Like [`Self::div`], dividing by zero never finishes. Uses temporary
+    /// variables `0` to `7`, and [`WIDE_B`] for a copy of `a`, [`WIDE_FLAG`] and [`WIDE_ZERO`]
+    fn div_wide(&mut self, a: &Variable, b: &Variable, remainder: &Variable, quotient: &Variable) {
+        let (rest, flag) = (WIDE_A.to_string(), WIDE_FLAG.to_string());
+        let rest_bytes = self.bytes(&rest);
+        let dividend = self.bytes(WIDE_B);
+        let quotient_bytes = self.bytes(quotient);
+        let cells = self.bytes(a).len();
+        self.copy_wide(a, WIDE_B);
+        self.zero_wide(quotient);
+
+        for i in (0..cells).rev() {
+            for k in (1..rest_bytes.len()).rev() {
+                self.move_value(&rest_bytes[k - 1], &rest_bytes[k]);
+            }
+            self.move_value(&dividend[i], &rest_bytes[0]);
+
+            self.comparison(Relation::GreaterEqual, &rest, b);
+            self.move_value(&"2".to_string(), &flag);
+            self.while_not_zero(&flag);
+            self.sub_wide(&rest, b);
+            if let Some(byte) = quotient_bytes.get(i) {
+                self.inc_by(byte, &1);
+            }
+            self.comparison(Relation::GreaterEqual, &rest, b);
+            self.move_value(&"2".to_string(), &flag);
+            self.end();
+        }
+
+        self.copy_wide(&rest, remainder);
+        self.zero_wide(&rest);
+        self.goto(quotient);
+    }
+
+    /// [`Self::compare`] for the numbers in the cells `a` and `b`, lowest first, comparing cells
+    /// from the highest down
+    ///
+    /// The highest cell that differs decides, missing cells of the shorter number read
+    /// [`WIDE_ZERO`]. Uses temporary variables `0` to `3` and `6`
+    fn compare_bytes(&mut self, a: &[Variable], b: &[Variable], res: &Variable) {
+        let (zero, cell) = (WIDE_ZERO.to_string(), "6".to_string());
+        self.zero(res);
+        for i in (0..a.len().max(b.len())).rev() {
+            let x = a.get(i).unwrap_or(&zero);
+            let y = b.get(i).unwrap_or(&zero);
+            self.compare(x, y, &cell);
+            self.if_equal_const(res, &0);
+            self.move_value(&cell, res);
+            self.end();
+            self.zero(&cell);
+        }
+        self.goto(res);
+    }
+
+    /// Whether `a` was declared with `svar`
+    fn is_signed(&self, a: &Variable) -> bool {
+        self.parsed.signed.contains(a)
+    }
+
+    /// Flip the sign bits of `a` and `b`, so two's complement values order like unsigned ones
+    ///
+    /// Flipping them again restores them.
+    fn flip_signs(&mut self, a: &Variable, b: &Variable) {
+        self.inc_by(a, &128);
+        if a != b {
+            self.inc_by(b, &128);
+        }
+    }
+
+    /// Set a variable to its two's complement negation
+    ///
+    /// Uses temporary variable `0`
+    fn negate(&mut self, a: &Variable) {
+        self.move_value(a, &"0".to_string());
+        self.while_not_zero(&"0".to_string());
+        self.dec_by(&"0".to_string(), &1);
+        self.dec_by(a, &1);
+        self.end();
+        self.goto(a);
+    }
+
+    /// Copy variable `from` to `to` whatever their signedness, sign extending signed values into
+    /// wide variables
+    ///
+    /// Uses temporary variables `0` to `6`
+    fn cast(&mut self, from: &Variable, to: &Variable) {
+        self.copy_wide(from, to);
+        if self.is_signed(from) && self.is_wide(to) {
+            self.byte_comparison_const(Relation::GreaterEqual, from, 128);
+            self.compare_block(BlockStack::IfCompare);
+            for byte in &self.bytes(to)[1..] {
+                self.set(byte, &255);
+            }
+            self.end();
+        }
+        self.goto(to);
+    }
+
+    /// [`Self::div`] for signed variables, truncating toward zero
+    ///
+    /// Divides the magnitudes in temp10 and temp11, then negates the quotient if the signs differ
+    /// and the remainder if `a` is negative. Uses temporary variables `0` to `13`
+    fn div_signed(
+        &mut self,
+        a: &Variable,
+        b: &Variable,
+        remainder: &Variable,
+        quotient: &Variable,
+    ) {
+        let (x, y) = ("10".to_string(), "11".to_string());
+        let (sign_a, sign_b) = ("12".to_string(), "13".to_string());
+        for (from, to, sign) in [(a, &x, &sign_a), (b, &y, &sign_b)] {
+            self.copy(from, to);
+            self.byte_comparison_const(Relation::GreaterEqual, to, 128);
+            self.move_value(&"2".to_string(), sign);
+            self.if_not_equal_const(sign, &0);
+            self.negate(to);
+            self.end();
+        }
+        self.div(&x, &y, remainder, quotient);
+        self.zero(&x);
+        self.zero(&y);
+
+        self.if_not_equal_const(&sign_a, &0);
+        self.negate(quotient);
+        self.negate(remainder);
+        self.end();
+        self.if_not_equal_const(&sign_b, &0);
+        self.negate(quotient);
+        self.end();
+
+        self.zero(&sign_a);
+        self.zero(&sign_b);
+        self.goto(quotient);
+    }
+
     fn read(&mut self, a: &Variable) {
         self.goto(a);
         self.zero(a); // Depends on implementation, but the debugger I'm using needs this
@@ -567,13 +1601,108 @@ impl Codegen {
         self.code += ",";
     }
 
+    /// Read a byte, setting `eof` to whether the input ended
+    ///
+    /// For the `Max` and `Unchanged` policies the cell is set to -1 before reading, which is only
+    /// left there at the end of input, as a byte is at most 255. So this is exact with cells wider
+    /// than 8 bits. With the `Zero` policy, a 0 byte is the end of input.
+    ///
+    /// Uses temporary variables `0` and `2`
+    fn read_eof(&mut self, a: &Variable, eof: &Variable) {
+        self.set(eof, &1);
+        match self.eof {
+            EofPolicy::Zero => {
+                self.read(a);
+                self.if_not_equal_const(a, &0);
+                self.zero(eof);
+                self.end();
+            }
+            EofPolicy::Max | EofPolicy::Unchanged => {
+                self.zero(a);
+                self.code += "-,+";
+                self.if_not_equal_const(a, &0);
+                self.zero(eof);
+                self.dec_by(a, &1);
+                self.end();
+            }
+        }
+        self.goto(a);
+    }
+
+    /// Read a decimal number, setting `flag` to whether there was one
+    ///
+    /// Uses temporary variables `0` to `5` and `10` to `14`, and `7` and [`WIDE_A`] if `a` is
+    /// wide
+    fn readn(&mut self, a: &Variable, flag: Option<&Variable>) {
+        self.zero_wide(a);
+        if let Some(flag) = flag {
+            self.zero(flag);
+        }
+
+        // Temp10 is the last character, read until it is not whitespace
+        self.set(&"11".to_string(), &1);
+        self.while_not_zero(&"11".to_string());
+        self.read(&"10".to_string());
+        self.zero(&"11".to_string());
+        for whitespace in [b' ', b'\t', b'\n', b'\r'] {
+            self.if_equal_const(&"10".to_string(), &whitespace);
+            self.set(&"11".to_string(), &1);
+            self.end();
+        }
+        self.end();
+
+        // Add digits while temp10 is one, `c - '0' < 10` also catches characters below `0`
+        self.set(&"13".to_string(), &10);
+        self.set(&"11".to_string(), &1);
+        self.while_not_zero(&"11".to_string());
+        self.dec_by(&"10".to_string(), &b'0');
+        self.compare(&"10".to_string(), &"13".to_string(), &"14".to_string());
+        self.zero(&"11".to_string());
+
+        self.if_equal_const(&"14".to_string(), &1);
+        if self.is_wide(a) {
+            self.mul_ten_wide(a);
+            self.add_wide(a, "10");
+        } else {
+            self.mul(a, &"13".to_string());
+            self.add(a, &"10".to_string());
+        }
+        if let Some(flag) = flag {
+            self.set(flag, &1);
+        }
+        self.set(&"11".to_string(), &1);
+        self.read(&"10".to_string());
+        self.end();
+
+        self.end();
+
+        // Zero out temp variables
+        self.zero(&"10".to_string());
+        self.zero(&"13".to_string());
+        self.zero(&"14".to_string());
+        self.goto(a);
+    }
+
+    /// Multiply a wide variable by ten, adding a copy of it in [`WIDE_A`] nine times
+    ///
+    /// Uses temporary variables `0` to `3` and `7`
+    fn mul_ten_wide(&mut self, a: &Variable) {
+        self.copy_wide(a, WIDE_A);
+        for _ in 1..10 {
+            self.add_wide(a, WIDE_A);
+        }
+        self.zero_wide(WIDE_A);
+    }
+
     fn print(&mut self, a: &Variable) {
         self.goto(a);
         self.code += ".";
     }
 
-    /// Pretty print a number
-    fn printc(&mut self, a: &Variable) {
+    /// Pretty print a number, padded to `width` characters with `pad`
+    ///
+    /// Uses temporary variables `0` to `15`
+    fn printc(&mut self, a: &Variable, width: Immediate, pad: Immediate) {
         self.set(&"15".to_string(), &100);
         self.div(a, &"15".to_string(), &"10".to_string(), &"11".to_string());
 
@@ -588,44 +1717,225 @@ impl Codegen {
         // So temp11 is hundreds, temp14 is tens, temp12 is ones
         self.set(&"15".to_string(), &0);
 
-        self.if_not_equal_const(&"11".to_string(), &0);
-        self.inc_by(&"11".to_string(), &48);
-        self.print(&"11".to_string());
+        // Padding beyond three digits is always printed
+        if width > 3 {
+            self.set(&"15".to_string(), &pad);
+            for _ in 3..width {
+                self.print(&"15".to_string());
+            }
+            self.zero(&"15".to_string());
+        }
+
+        // Temp13 is set once a digit is printed, so zeros after it are printed too
+        self.print_digit(&"11".to_string(), width >= 3, pad);
+        self.print_digit(&"14".to_string(), width >= 2, pad);
+        self.inc_by(&"12".to_string(), &b'0');
+        self.print(&"12".to_string());
+
+        // Zero out temp variables
+        self.zero(&"10".to_string());
+        self.zero(&"11".to_string());
+        self.zero(&"12".to_string());
+        self.zero(&"13".to_string());
+        self.zero(&"14".to_string());
+        self.zero(&"15".to_string());
+    }
+
+    /// [`Self::printc`] for signed variables, printing negative values as `-` and their magnitude
+    ///
+    /// Padding goes before the sign, or between the sign and the digits when padding with zeros.
+    /// Uses temporary variables `0` to `15`, and [`SIGN`]
+    fn printc_signed(&mut self, a: &Variable, width: Immediate, pad: Immediate) {
+        let sign = SIGN.to_string();
+        self.byte_comparison_const(Relation::GreaterEqual, a, 128);
+        self.move_value(&"2".to_string(), &sign);
+
+        self.if_not_equal_const(&sign, &0);
+        self.negate(a);
+        if pad == b'0' {
+            self.prints("-".to_string());
+            self.printc(a, width.saturating_sub(1), pad);
+        } else {
+            // The magnitude has up to three digits, the sign goes right before the first
+            if width > 4 {
+                self.set(&"15".to_string(), &pad);
+                for _ in 4..width {
+                    self.print(&"15".to_string());
+                }
+                self.zero(&"15".to_string());
+            }
+            for (digits, limit) in [(3, 100), (2, 10)] {
+                if width > digits {
+                    self.byte_comparison_const(Relation::Less, a, limit);
+                    self.compare_block(BlockStack::IfCompare);
+                    self.set(&"15".to_string(), &pad);
+                    self.print(&"15".to_string());
+                    self.zero(&"15".to_string());
+                    self.end();
+                }
+            }
+            self.prints("-".to_string());
+            self.printc(a, 0, pad);
+        }
+        self.negate(a);
         self.end();
 
-        self.if_not_equal_const(&"14".to_string(), &0);
-        self.inc_by(&"14".to_string(), &48);
-        self.print(&"14".to_string());
+        self.if_equal_const(&sign, &0);
+        self.printc(a, width, pad);
         self.end();
+        self.zero(&sign);
+    }
 
-        self.if_not_equal_const(&"12".to_string(), &0);
-        self.inc_by(&"12".to_string(), &48);
-        self.print(&"12".to_string());
+    /// [`Self::printc`] for wide variables
+    ///
+    /// Each digit is counted in [`WIDE_DIGIT`] by subtracting its power of ten from a copy of
+    /// `a` in [`WIDE_A`], until the ones are left. Only the cells that can hold the rest of the
+    /// number are compared and subtracted. Uses temporary variables `0` to `6`, `13` and `15`,
+    /// and [`WIDE_B`] and [`WIDE_FLAG`]
+    fn printc_wide(&mut self, a: &Variable, width: Immediate, pad: Immediate) {
+        let (digit, flag) = (WIDE_DIGIT.to_string(), WIDE_FLAG.to_string());
+        let bytes = self.bytes(a);
+        let rest = self.bytes(WIDE_A)[..bytes.len()].to_vec();
+        let digits = (Constant::MAX >> (32 - 8 * bytes.len())).to_string().len() as u32;
+        let width = width as u32;
+
+        for (from, to) in bytes.iter().zip(&rest) {
+            self.copy(from, to);
+        }
+
+        // Padding beyond the largest number of digits is always printed
+        if width > digits {
+            self.set(&"15".to_string(), &pad);
+            for _ in digits..width {
+                self.print(&"15".to_string());
+            }
+            self.zero(&"15".to_string());
+        }
+
+        for place in (1..digits).rev() {
+            let power = 10u32.pow(place);
+            // The rest is less than ten times the power here
+            let max = 10u64.pow(place + 1) - 1;
+            let rest = &rest[..(max.ilog2() as usize / 8 + 1).min(rest.len())];
+
+            self.bytes_comparison_const(Relation::GreaterEqual, rest, power);
+            self.move_value(&"2".to_string(), &flag);
+            self.while_not_zero(&flag);
+            self.dec_by_bytes(rest, power);
+            self.inc_by(&digit, &1);
+            self.bytes_comparison_const(Relation::GreaterEqual, rest, power);
+            self.move_value(&"2".to_string(), &flag);
+            self.end();
+
+            self.print_digit(&digit, width > place, pad);
+            self.zero(&digit);
+        }
+        self.inc_by(&rest[0], &b'0');
+        self.print(&rest[0]);
+
+        // Zero out temp variables
+        self.zero(&rest[0]);
+        self.zero(&"13".to_string());
+    }
+
+    /// Print a leading decimal digit of [`Self::printc`] unless it and all digits before it are
+    /// zero, then print `pad` if `padded`
+    ///
+    /// Uses temporary variables `0` to `3`, `13` and `15`
+    fn print_digit(&mut self, digit: &Variable, padded: bool, pad: Immediate) {
+        self.if_not_equal_const(digit, &0);
+        self.set(&"13".to_string(), &1);
         self.end();
 
+        self.if_not_equal_const(&"13".to_string(), &0);
+        self.inc_by(digit, &b'0');
+        self.print(digit);
+        self.end();
+
+        if padded {
+            self.if_equal_const(&"13".to_string(), &0);
+            self.set(&"15".to_string(), &pad);
+            self.print(&"15".to_string());
+            self.zero(&"15".to_string());
+            self.end();
+        }
+    }
+
+    /// Print a number as two hexadecimal digits
+    ///
+    /// Uses temporary variables `0` to `15`
+    fn printx(&mut self, a: &Variable) {
+        self.set(&"15".to_string(), &16);
+        self.div(a, &"15".to_string(), &"10".to_string(), &"11".to_string());
+
+        // So temp11 is the high digit, temp10 is the low digit
+        self.print_hex_digit(&"11".to_string());
+        self.print_hex_digit(&"10".to_string());
+
         // Zero out temp variables
         self.zero(&"10".to_string());
         self.zero(&"11".to_string());
         self.zero(&"12".to_string());
-        self.zero(&"13".to_string());
         self.zero(&"14".to_string());
         self.zero(&"15".to_string());
     }
 
-    fn prints(&mut self, msg: String) {
-        let msg = msg
-            .replace("\\n", "\n")
-            .replace("\\t", "\t")
-            .replace("\\r", "\r");
+    /// Print a digit from 0 to 15 in hexadecimal
+    ///
+    /// Uses temporary variables `0` to `9`, `12`, `14` and `15`
+    fn print_hex_digit(&mut self, digit: &Variable) {
+        // Temp14 is 1 for letters
+        self.set(&"15".to_string(), &10);
+        self.div(
+            digit,
+            &"15".to_string(),
+            &"12".to_string(),
+            &"14".to_string(),
+        );
+
+        self.inc_by(digit, &b'0');
+        self.if_not_equal_const(&"14".to_string(), &0);
+        self.inc_by(digit, &(b'a' - b'0' - 10));
+        self.end();
+        self.print(digit);
+    }
+
+    /// Print a number as eight binary digits
+    ///
+    /// Uses temporary variables `0` to `12` and `15`
+    fn printb(&mut self, a: &Variable) {
+        // Temp10 is what is left to print, temp12 is the current bit
+        self.copy(a, &"10".to_string());
+        for bit in (0..8).rev() {
+            self.set(&"15".to_string(), &(1 << bit));
+            self.div(
+                &"10".to_string(),
+                &"15".to_string(),
+                &"11".to_string(),
+                &"12".to_string(),
+            );
+            self.move_value(&"11".to_string(), &"10".to_string());
+            self.inc_by(&"12".to_string(), &b'0');
+            self.print(&"12".to_string());
+        }
+
+        // Zero out temp variables
+        self.zero(&"10".to_string());
+        self.zero(&"12".to_string());
+        self.zero(&"15".to_string());
+    }
 
+    fn prints(&mut self, msg: String) {
         let mut last = 0;
         self.zero(&"0".to_string());
-        for c in msg.chars() {
+        // Text outside of ASCII is printed as UTF-8
+        for c in msg.bytes() {
             let diff = c as i32 - last;
+            let scratch = "1".to_string();
             if diff > 0 {
-                self.inc_by(&"0".to_string(), &(diff as u8));
+                self.add_constant(&"0".to_string(), diff as u8, '+', &scratch);
             } else {
-                self.dec_by(&"0".to_string(), &(-diff as u8));
+                self.add_constant(&"0".to_string(), -diff as u8, '-', &scratch);
             }
 
             self.code += ".";
@@ -635,15 +1945,77 @@ impl Codegen {
     }
 
     /// Increment a variable by number
+    ///
+    /// Uses temporary variable `16` for large numbers
     fn inc_by(&mut self, a: &Variable, b: &Immediate) {
-        self.goto(a);
-        self.code += &*"+".repeat(*b as usize);
+        self.add_constant(a, *b, '+', &"16".to_string());
     }
 
     /// Decrement a variable by number
+    ///
+    /// Uses temporary variable `16` for large numbers
     fn dec_by(&mut self, a: &Variable, b: &Immediate) {
+        self.add_constant(a, *b, '-', &"16".to_string());
+    }
+
+    /// Add (`op` is `+`) or subtract (`op` is `-`) `n` to a variable
+    ///
+    /// Emits either `n` times `op`, or a multiplication loop on the zero temporary `scratch`, like
+    /// `++++++++++[->++++++++++<]>` for 100. The loop is used when it is shorter, unless the
+    /// optimization level prefers the speed of the literal.
+    fn add_constant(&mut self, a: &Variable, n: Immediate, op: char, scratch: &Variable) {
+        let start = self.code.len();
+        let ptr = self.ptr;
+
         self.goto(a);
-        self.code += &*"-".repeat(*b as usize);
+        self.code += &op.to_string().repeat(n as usize);
+        if self.level == OptLevel::O0 {
+            return;
+        }
+        let literal = self.code.split_off(start);
+        let end = self.ptr;
+
+        let Some((times, factor, rest)) = Self::factorize(n) else {
+            self.code += &literal;
+            return;
+        };
+        let inverse = if op == '+' { '-' } else { '+' };
+
+        self.ptr = ptr;
+        self.goto(scratch);
+        self.code += &"+".repeat(times as usize);
+        self.code += "[-";
+        self.goto(a);
+        self.code += &op.to_string().repeat(factor as usize);
+        self.goto(scratch);
+        self.code += "]";
+        self.goto(a);
+        let rest_op = if rest < 0 { inverse } else { op };
+        self.code += &rest_op.to_string().repeat(rest.unsigned_abs() as usize);
+        let looped = self.code.split_off(start);
+
+        // The literal is always faster
+        if self.level.accept_faster(looped.len(), literal.len()) {
+            self.code += &literal;
+        } else {
+            self.code += &looped;
+        }
+        debug_assert_eq!(self.ptr, end);
+    }
+
+    /// Write `n` as `times * factor + rest` with the smallest `times + factor + |rest|`
+    fn factorize(n: Immediate) -> Option<(u32, u32, i32)> {
+        let n = n as i32;
+        (2..=16)
+            .flat_map(|times| {
+                let factor = n / times;
+                [factor, factor + 1]
+                    .into_iter()
+                    .filter(|&factor| factor >= 2)
+                    .map(move |factor| (times, factor, n - times * factor))
+            })
+            .min_by_key(|&(times, factor, rest)| times + factor + rest.abs())
+            .map(|(times, factor, rest)| (times as u32, factor as u32, rest))
     }
 
     /// Zero out a variable
@@ -716,6 +2088,65 @@ impl Codegen {
         self.goto(a);
     }
 
+    /// Copy element `idx` of array `arr` to `dst`, see [`array_cursor`] for how
+    ///
+    /// Uses temporary variable `0`, or `0` to `6` with sanitizer checks
+    fn load(&mut self, arr: &Variable, idx: &Variable, dst: &Variable) {
+        let (counter, value) = array_cursor(arr);
+        self.array_access(arr, idx, |codegen| {
+            codegen.copy(idx, &counter);
+            codegen.goto(&counter);
+
+            // Walk to the element, leaving a trail of ones
+            codegen.code += "[-[->>>+<<<]+>>>]";
+            // Copy the element to the value next to it
+            codegen.code += ">>[-<+<+>>]<<[->>+<<]";
+            // Carry the value back along the trail, ending at the zero cell before the array
+            codegen.code += "<<<[->>>>[-<<<+>>>]<<<<<<<]";
+            codegen.ptr -= 3;
+        });
+        self.move_value(&value, dst);
+        self.goto(dst);
+    }
+
+    /// Copy `src` to element `idx` of array `arr`, see [`array_cursor`] for how
+    ///
+    /// Uses temporary variable `0`, or `0` to `6` with sanitizer checks
+    fn store(&mut self, arr: &Variable, idx: &Variable, src: &Variable) {
+        let (counter, value) = array_cursor(arr);
+        self.array_access(arr, idx, |codegen| {
+            codegen.copy(idx, &counter);
+            codegen.copy(src, &value);
+            codegen.goto(&counter);
+
+            // Walk to the element carrying the value, leaving a trail of ones
+            codegen.code += "[-[->>>+<<<]+>[->>>+<<<]>>]";
+            // Replace the element with the value
+            codegen.code += ">>[-]<[->+<]<";
+            // Clear the trail, ending at the zero cell before the array
+            codegen.code += "<<<[-<<<]";
+            codegen.ptr -= 3;
+        });
+    }
+
+    /// Generate the array access `access`, only running it if `idx` is in range of `arr` when
+    /// sanitizer checks are on and setting [`TRAP`] otherwise
+    fn array_access(&mut self, arr: &Variable, idx: &Variable, access: impl FnOnce(&mut Self)) {
+        if !self.sanitize {
+            access(self);
+            return;
+        }
+
+        let (_, len) = self.parsed.arrays[arr];
+        let trap = TRAP.to_string();
+        self.set(&trap, &1);
+        self.byte_comparison_const(Relation::Less, idx, len);
+        self.compare_block(BlockStack::IfCompare);
+        self.zero(&trap);
+        access(self);
+        self.end();
+    }
+
     /// Move pointer to end of variables
     fn goto_end_of_vars(&mut self) {
         self.move_by(self.parsed.variable_count - self.ptr);
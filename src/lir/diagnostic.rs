@@ -0,0 +1,112 @@
+use crate::lir::analysis::AnalysisError;
+use crate::lir::codegen::CodegenError;
+use crate::lir::parser::Span;
+use std::fmt::Display;
+use thiserror::Error;
+
+/// A compile error at a location in the source code
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{message}")]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Display, span: Span) -> Self {
+        Self {
+            message: message.to_string(),
+            span,
+        }
+    }
+
+    /// Render like rustc, with the location and the source line with a caret under the error
+    ///
+    /// ```text
+    /// error: Variable y must be assigned before use
+    ///  --> example.lir:2:7
+    ///   |
+    /// 2 | print y
+    ///   |       ^
+    /// ```
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let line = source
+            .lines()
+            .nth(self.span.line.saturating_sub(1))
+            .unwrap_or("")
+            .trim_end_matches('\r');
+        let number = self.span.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        // Keep tabs so the caret lines up with the source
+        let indent: String = line
+            .chars()
+            .take(self.span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let start = self.span.start.min(source.len());
+        let rest = source[start..].split('\n').next().unwrap_or("");
+        let length = (self.span.end.saturating_sub(start)).min(rest.len());
+        let width = rest[..length].trim_end_matches('\r').chars().count().max(1);
+
+        format!(
+            "error: {}\n{gutter}--> {file}:{}:{}\n{gutter} |\n{number} | {line}\n{gutter} | {indent}{}",
+            self.message,
+            self.span.line,
+            self.span.column,
+            "^".repeat(width)
+        )
+    }
+}
+
+/// All errors found in a program
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"))]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    /// Render every diagnostic like [`Diagnostic::render`], followed by how many there are
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let mut out = String::new();
+        for diagnostic in &self.0 {
+            out += &diagnostic.render(file, source);
+            out += "\n\n";
+        }
+
+        let count = self.0.len();
+        let plural = if count == 1 { "" } else { "s" };
+        out + &format!("error: could not compile `{file}` due to {count} previous error{plural}")
+    }
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self(vec![diagnostic])
+    }
+}
+
+/// Turn errors about instructions into [`Diagnostics`] pointing at them
+///
+/// Errors that name a variable point at the variable. Other errors are returned as they are.
+pub fn locate(error: anyhow::Error, source: &str, spans: &[Span]) -> anyhow::Error {
+    let locate_one = |message: String, instruction: usize, variable: Option<&str>| {
+        let span = spans[instruction];
+        let span = match variable {
+            Some(v) => span.word(source, v),
+            None => span,
+        };
+        Diagnostic::new(message, span)
+    };
+
+    if let Some(AnalysisError(errors)) = error.downcast_ref() {
+        let diagnostics = errors
+            .iter()
+            .map(|e| locate_one(e.to_string(), e.instruction(), e.variable()))
+            .collect();
+        Diagnostics(diagnostics).into()
+    } else if let Some(e) = error.downcast_ref::<CodegenError>() {
+        Diagnostics::from(locate_one(e.to_string(), e.instruction(), e.variable())).into()
+    } else {
+        error
+    }
+}
@@ -1,34 +1,100 @@
+use std::str::FromStr;
+
 pub type Immediate = u8;
+/// Constant operand of an instruction on a variable, up to the largest value of the variable
+///
+/// Negative literals are wrapped around by the parser to the width of the variable.
+pub type Constant = u32;
 pub type Variable = String;
 
+/// How `a` has to be ordered against `b` for an ordering block to run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    /// `lt`
+    Less,
+    /// `gt`
+    Greater,
+    /// `le`
+    LessEqual,
+    /// `ge`
+    GreaterEqual,
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lt" => Ok(Comparison::Less),
+            "gt" => Ok(Comparison::Greater),
+            "le" => Ok(Comparison::LessEqual),
+            "ge" => Ok(Comparison::GreaterEqual),
+            _ => Err(format!(
+                "Invalid comparison `{s}`, expected lt, gt, le or ge"
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     /// Copy from variable a to variable b, zeroing a
     Copy { a: Variable, b: Variable },
 
+    /// Declare a variable `bytes` cells wide and set it to zero, `var16` or `var32`
+    ///
+    /// The cells are little endian, so the variable is its lowest byte to instructions that only
+    /// work on single cells. Carries between cells need 8 bit cells.
+    Wide { name: Variable, bytes: u8 },
+
+    /// Declare a signed variable and set it to zero, `svar`
+    ///
+    /// Its values are 8 bit two's complement, which `compare`, `div`, `printc` and the ordering
+    /// conditions use.
+    Signed(Variable),
+
+    /// Copy variable a to variable b, which may differ in signedness or width
+    ///
+    /// Signed values are sign extended into wide variables.
+    Cast { a: Variable, b: Variable },
+
     /// Set variable to value
-    Set(Variable, Immediate),
+    Set(Variable, Constant),
 
     /// Increment variable by one
     Inc(Variable),
     /// Decrement variable by one
     Dec(Variable),
     /// Increment variable by value
-    IncBy(Variable, Immediate),
+    IncBy(Variable, Constant),
     /// Decrement variable by value
-    DecBy(Variable, Immediate),
+    DecBy(Variable, Constant),
 
     /// Read STDIN into variable
     ///
     /// Make sure to zero out the variable if it's in a loop
     Read(Variable),
 
+    /// Read STDIN into variable `a`, setting `eof` to 1 and `a` to 0 at the end of input
+    ///
+    /// Only exact for the EOF policy the code is generated for. With 8 bit cells, a 0 byte is
+    /// end of input when it is 0 and 255 is end of input otherwise.
+    ReadEof { a: Variable, eof: Variable },
+
+    /// Read a decimal number from STDIN into variable `a`
+    ///
+    /// Skips leading whitespace and stops at the first character that is not a digit, which is
+    /// read and lost. Numbers above 255 wrap around. If `flag` is given, it is set to 1 if a digit
+    /// was read and 0 otherwise.
+    ReadN { a: Variable, flag: Option<Variable> },
+
     /// Print variable to STDOUT
     Print(Variable),
 
     /// Print a string to STDOUT
     ///
-    /// Includes escapes for newlines, carriage return and tabs
+    /// Escapes are already replaced by the parser, characters outside of ASCII are printed as
+    /// UTF-8
     PrintS(String),
 
     /// Print variable as a human-readable number
@@ -36,6 +102,26 @@ pub enum Instruction {
     /// E.g. 0x10 will print as `10`
     PrintC(Variable),
 
+    /// Print variable as a decimal number at least `width` characters wide, filled with `pad` on
+    /// the left
+    ///
+    /// E.g. 7 with a width of 3 and `pad` of `'0'` will print as `007`
+    PrintCPadded {
+        a: Variable,
+        width: Immediate,
+        pad: Immediate,
+    },
+
+    /// Print variable as two lowercase hexadecimal digits
+    ///
+    /// E.g. 10 will print as `0a`
+    PrintX(Variable),
+
+    /// Print variable as eight binary digits
+    ///
+    /// E.g. 10 will print as `00001010`
+    PrintB(Variable),
+
     /// Add variable `b` to variable `a`
     ///
     /// Equivalent to `a += b`.
@@ -61,6 +147,56 @@ pub enum Instruction {
         remainder: Variable,
     },
 
+    /// Bitwise and of two variables
+    ///
+    /// Equivalent to `a &= b`.
+    And { a: Variable, b: Variable },
+
+    /// Bitwise or of two variables
+    ///
+    /// Equivalent to `a |= b`.
+    Or { a: Variable, b: Variable },
+
+    /// Bitwise exclusive or of two variables
+    ///
+    /// Equivalent to `a ^= b`.
+    Xor { a: Variable, b: Variable },
+
+    /// Flip every bit of a variable
+    ///
+    /// Equivalent to `a = !a`.
+    Not(Variable),
+
+    /// Shift a variable left by a number of bits, dropping the bits shifted out
+    ///
+    /// Equivalent to `a <<= n`.
+    Shl(Variable, Immediate),
+
+    /// Shift a variable right by a number of bits, shifting in zeros
+    ///
+    /// Equivalent to `a >>= n`.
+    Shr(Variable, Immediate),
+
+    /// Logical and of two booleans, 0 or 1
+    ///
+    /// Equivalent to `a = a && b`. `b` can be any value, nonzero being true.
+    LogicalAnd { a: Variable, b: Variable },
+
+    /// Logical or of two booleans, 0 or 1
+    ///
+    /// Equivalent to `a = a || b`. `b` can be any value, nonzero being true.
+    LogicalOr { a: Variable, b: Variable },
+
+    /// Logical not of a boolean, 0 or 1
+    ///
+    /// Equivalent to `a = !a`.
+    LogicalNot(Variable),
+
+    /// Turn a variable into a boolean, 1 if it is not zero
+    ///
+    /// Equivalent to `a = a != 0`.
+    Bool(Variable),
+
     /// Execute code only if `a` equals `b`
     ///
     /// All If and While loops CANNOT touch any temporary variables after the body ends.
@@ -68,10 +204,10 @@ pub enum Instruction {
     IfEqual { a: Variable, b: Variable },
 
     /// Execute code only if `a` equals constant
-    IfEqualConst { a: Variable, b: Immediate },
+    IfEqualConst { a: Variable, b: Constant },
 
     /// Execute code only if `a` doesn't equal constant
-    IfNotEqualConst { a: Variable, b: Immediate },
+    IfNotEqualConst { a: Variable, b: Constant },
 
     /// Execute code only if `a` does not equal `b`
     IfNotEqual { a: Variable, b: Variable },
@@ -82,6 +218,53 @@ pub enum Instruction {
     /// Execute code while `a` is not zero
     WhileNotZero(Variable),
 
+    /// Execute code only if `a` is not zero, cheaper than `if_neq a 0`
+    IfNotZero(Variable),
+
+    /// Execute code only if `a` is zero, cheaper than `if_eq a 0`
+    IfZero(Variable),
+
+    /// Execute code only if `a` is ordered against `b` like `op` says, e.g. `if_lt a b`
+    IfCompare {
+        op: Comparison,
+        a: Variable,
+        b: Variable,
+    },
+
+    /// Execute code only if `a` is ordered against a constant like `op` says, e.g. `if_lt a 10`
+    IfCompareConst {
+        op: Comparison,
+        a: Variable,
+        b: Constant,
+    },
+
+    /// Execute code while `a` is ordered against `b` like `op` says, e.g. `while_lt a b`
+    ///
+    /// The condition is checked again at the end of every iteration.
+    WhileCompare {
+        op: Comparison,
+        a: Variable,
+        b: Variable,
+    },
+
+    /// Execute code while `a` is ordered against a constant like `op` says, e.g. `while_lt a 10`
+    WhileCompareConst {
+        op: Comparison,
+        a: Variable,
+        b: Constant,
+    },
+
+    /// Start the block of an if block that runs when the condition was false
+    Else,
+
+    /// Leave the loop this many loops out, `1` being the innermost
+    Break(Immediate),
+
+    /// Skip the rest of the body of the loop this many loops out, `1` being the innermost
+    ///
+    /// Loops inside it are left.
+    Continue(Immediate),
+
     /// End clause to end if/until blocks
     End,
 
@@ -97,6 +280,32 @@ pub enum Instruction {
         res: Variable,
     },
 
+    /// Declare an array of `len` variables, which start at zero
+    ///
+    /// Arrays take three cells per element and three more, and can only be used by `load` and
+    /// `store`.
+    Array { name: Variable, len: Immediate },
+
+    /// Copy element `idx` of array `arr` to `dst`
+    ///
+    /// An index past the end of the array reads and writes the cells after it, which belong to
+    /// other variables or the stack. Code generated with sanitizer checks stops with an error
+    /// instead.
+    Load {
+        arr: Variable,
+        idx: Variable,
+        dst: Variable,
+    },
+
+    /// Copy `src` to element `idx` of array `arr`
+    ///
+    /// Out of range indices are handled like in [`Instruction::Load`].
+    Store {
+        arr: Variable,
+        idx: Variable,
+        src: Variable,
+    },
+
     /// Push variable onto stack
     Push(Variable),
 
@@ -120,3 +329,131 @@ pub enum Instruction {
     /// Only use if you have to, must put pointer back into position after every use
     Raw(String),
 }
+
+impl Instruction {
+    /// All variables the instruction uses, in the order they are written
+    ///
+    /// Arrays are not variables, see [`Instruction::array`].
+    pub fn variables(&self) -> Vec<&Variable> {
+        use Instruction::*;
+
+        match self {
+            Copy { a, b }
+            | Cast { a, b }
+            | Add { a, b }
+            | Sub { a, b }
+            | Mul { a, b }
+            | And { a, b }
+            | Or { a, b }
+            | Xor { a, b }
+            | LogicalAnd { a, b }
+            | LogicalOr { a, b }
+            | IfEqual { a, b }
+            | IfNotEqual { a, b }
+            | UntilEqual { a, b }
+            | IfCompare { a, b, .. }
+            | WhileCompare { a, b, .. } => vec![a, b],
+            ReadEof { a, eof } => vec![a, eof],
+            Div {
+                a,
+                b,
+                quotient,
+                remainder,
+            } => vec![a, b, remainder, quotient],
+            Compare { a, b, res } => vec![a, b, res],
+            Load { idx, dst, .. } => vec![idx, dst],
+            Store { idx, src, .. } => vec![idx, src],
+            ReadN { a, flag } => std::iter::once(a).chain(flag).collect(),
+            Set(a, _)
+            | Inc(a)
+            | Dec(a)
+            | IncBy(a, _)
+            | DecBy(a, _)
+            | Read(a)
+            | Print(a)
+            | PrintC(a)
+            | PrintCPadded { a, .. }
+            | PrintX(a)
+            | PrintB(a)
+            | Not(a)
+            | LogicalNot(a)
+            | Bool(a)
+            | IfNotZero(a)
+            | IfZero(a)
+            | Shl(a, _)
+            | Shr(a, _)
+            | IfEqualConst { a, .. }
+            | IfNotEqualConst { a, .. }
+            | IfCompareConst { a, .. }
+            | WhileCompareConst { a, .. }
+            | WhileNotZero(a)
+            | Wide { name: a, .. }
+            | Signed(a)
+            | Push(a)
+            | Pop(a)
+            | Match(a, _) => vec![a],
+            PrintS(_) | Array { .. } | Else | Break(_) | Continue(_) | End | Case() | Raw(_) => {
+                vec![]
+            }
+        }
+    }
+
+    /// The array the instruction declares or uses
+    pub fn array(&self) -> Option<&Variable> {
+        use Instruction::*;
+
+        match self {
+            Array { name: arr, .. } | Load { arr, .. } | Store { arr, .. } => Some(arr),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction starts a block that is ended by `end`
+    pub fn opens_block(&self) -> bool {
+        use Instruction::*;
+
+        matches!(
+            self,
+            IfEqual { .. }
+                | IfNotEqual { .. }
+                | UntilEqual { .. }
+                | WhileNotZero(..)
+                | IfNotZero(..)
+                | IfZero(..)
+                | IfNotEqualConst { .. }
+                | IfEqualConst { .. }
+                | IfCompare { .. }
+                | IfCompareConst { .. }
+                | WhileCompare { .. }
+                | WhileCompareConst { .. }
+                | Match(..)
+        )
+    }
+
+    /// Whether the instruction starts a loop, which `break` and `continue` jump out of
+    pub fn is_loop(&self) -> bool {
+        use Instruction::*;
+
+        matches!(
+            self,
+            UntilEqual { .. } | WhileNotZero(..) | WhileCompare { .. } | WhileCompareConst { .. }
+        )
+    }
+
+    /// Whether the block the instruction starts can have an `else`
+    pub fn is_if(&self) -> bool {
+        use Instruction::*;
+
+        matches!(
+            self,
+            IfEqual { .. }
+                | IfNotEqual { .. }
+                | IfNotEqualConst { .. }
+                | IfEqualConst { .. }
+                | IfNotZero(..)
+                | IfZero(..)
+                | IfCompare { .. }
+                | IfCompareConst { .. }
+        )
+    }
+}
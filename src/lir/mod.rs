@@ -1,5 +1,8 @@
-mod analysis;
+pub(crate) mod analysis;
 pub(crate) mod codegen;
+pub(crate) mod diagnostic;
 mod instruction;
+pub(crate) mod optim;
 pub(crate) mod parser;
+pub(crate) mod sourcemap;
 mod tests;
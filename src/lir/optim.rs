@@ -0,0 +1,85 @@
+use crate::lir::instruction::{Constant, Immediate, Instruction, Instruction::*, Variable};
+
+/// What a run of instructions does to a single variable
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Set(Constant),
+    /// Net increment, negative for decrements
+    Add(i64),
+}
+
+impl Change {
+    fn of(instruction: &Instruction) -> Option<(&Variable, Change)> {
+        match instruction {
+            Set(a, v) => Some((a, Change::Set(*v))),
+            Inc(a) => Some((a, Change::Add(1))),
+            Dec(a) => Some((a, Change::Add(-1))),
+            IncBy(a, v) => Some((a, Change::Add(*v as i64))),
+            DecBy(a, v) => Some((a, Change::Add(-(*v as i64)))),
+            _ => None,
+        }
+    }
+
+    /// Apply `next` after `self`, `None` if the result doesn't fit an immediate
+    ///
+    /// Values never wrap, so the result is the same for any cell width, and for variables of any
+    /// number of cells.
+    fn then(self, next: Change) -> Option<Change> {
+        match (self, next) {
+            (_, Change::Set(v)) => Some(Change::Set(v)),
+            (Change::Set(v), Change::Add(n)) => Immediate::try_from(v as i64 + n)
+                .ok()
+                .map(|v| Change::Set(v as Constant)),
+            (Change::Add(m), Change::Add(n)) => {
+                let sum = m + n;
+                (sum.unsigned_abs() <= Immediate::MAX as u64).then_some(Change::Add(sum))
+            }
+        }
+    }
+
+    fn instruction(self, a: Variable) -> Instruction {
+        match self {
+            Change::Set(v) => Set(a, v),
+            // Kept when it adds nothing, as it may be where the variable is first assigned
+            Change::Add(n) if n >= 0 => IncBy(a, n as Constant),
+            Change::Add(n) => DecBy(a, n.unsigned_abs() as Constant),
+        }
+    }
+}
+
+/// Merge runs of `set`, `inc`, `dec`, `inc_by` and `dec_by` on the same variable into the first
+/// instruction of the run
+///
+/// The merged instructions are replaced by empty `raw`, so every instruction keeps its span in
+/// the source map.
+pub fn fold_constants(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut out: Vec<Instruction> = Vec::with_capacity(instructions.len());
+    // Index in `out` of the first instruction of the current run and what the run does so far
+    let mut run: Option<(usize, Variable, Change)> = None;
+
+    for instruction in instructions {
+        if let (Some((_, a, change)), Some((b, next))) = (&mut run, Change::of(&instruction)) {
+            if a == b {
+                if let Some(merged) = change.then(next) {
+                    *change = merged;
+                    out.push(Raw(String::new()));
+                    continue;
+                }
+            }
+        }
+
+        if let Some((index, a, change)) = run.take() {
+            out[index] = change.instruction(a);
+        }
+        if let Some((a, change)) = Change::of(&instruction) {
+            run = Some((out.len(), a.clone(), change));
+        }
+        out.push(instruction);
+    }
+
+    if let Some((index, a, change)) = run {
+        out[index] = change.instruction(a);
+    }
+
+    out
+}
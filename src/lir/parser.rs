@@ -1,9 +1,13 @@
 // parser/mod.rs
-use crate::lir::instruction::Instruction;
+use crate::lir::diagnostic::{Diagnostic, Diagnostics};
+use crate::lir::instruction::{Comparison, Constant, Immediate, Instruction};
 use anyhow::Result;
+use pest::error::InputLocation;
 use pest::iterators::Pair;
 use pest::Parser;
 use pest_derive::Parser;
+use std::collections::{HashMap, HashSet};
+use std::num::IntErrorKind;
 use thiserror::Error;
 
 #[derive(Parser)]
@@ -14,18 +18,277 @@ struct LirParser;
 pub enum ParseError {
     #[error("Invalid instruction: {0}")]
     InvalidInstruction(String),
+
+    #[error("Invalid number `{number}`: {reason}")]
+    InvalidNumber { number: String, reason: String },
+
+    #[error("Invalid character `{literal}`: {reason}")]
+    InvalidCharacter { literal: String, reason: String },
+
+    #[error("Invalid string {literal}: {reason}")]
+    InvalidString { literal: String, reason: String },
+
+    #[error("`{literal}` is out of range, values must be between {min} and {max}")]
+    OutOfRange { literal: String, min: i64, max: u64 },
+}
+
+/// Location of an instruction in the source code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// Byte offset of the first character
+    pub start: usize,
+    /// Byte offset after the last character
+    pub end: usize,
+    /// Line number, starting at 1
+    pub line: usize,
+    /// Column number, starting at 1
+    pub column: usize,
+}
+
+impl Span {
+    fn from_pest(span: pest::Span) -> Self {
+        let (line, column) = span.start_pos().line_col();
+        Self {
+            start: span.start(),
+            end: span.end(),
+            line,
+            column,
+        }
+    }
+
+    /// Span of the bytes `start..end` of `source`
+    pub fn at(source: &str, start: usize, end: usize) -> Self {
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            start,
+            end,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// Span of the first whole `word` inside this span after its first word, or the span itself
+    ///
+    /// Used to point at an argument of an instruction.
+    pub fn word(self, source: &str, word: &str) -> Self {
+        let text = &source[self.start..self.end];
+        let is_part = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+        let first_end = text.find(|c: char| !is_part(c)).unwrap_or(text.len());
+
+        text.match_indices(word)
+            .map(|(i, _)| i)
+            .filter(|&i| i >= first_end)
+            .find(|&i| {
+                let before = text[..i].chars().next_back();
+                let after = text[i + word.len()..].chars().next();
+                !before.is_some_and(is_part) && !after.is_some_and(is_part)
+            })
+            .map_or(self, |i| {
+                Self::at(source, self.start + i, self.start + i + word.len())
+            })
+    }
+}
+
+/// Name of a grammar rule in error messages
+fn rule_name(rule: &Rule) -> String {
+    match rule {
+        Rule::EOI => "end of file".to_string(),
+        Rule::immediate => "number".to_string(),
+        Rule::string_literal => "string".to_string(),
+        rule => {
+            let name = format!("{rule:?}");
+            match name.strip_suffix("_instr") {
+                Some(instruction) => format!("`{instruction}`"),
+                None => name,
+            }
+        }
+    }
+}
+
+/// Turn an error of pest into a [`Diagnostic`]
+fn pest_diagnostic(error: pest::error::Error<Rule>, input: &str) -> Diagnostic {
+    let (start, end) = match error.location {
+        InputLocation::Pos(p) => (p, p),
+        InputLocation::Span(span) => span,
+    };
+    let error = error.renamed_rules(rule_name);
+    Diagnostic::new(error.variant.message(), Span::at(input, start, end))
+}
+
+/// Parse an `immediate` rule
+///
+/// Accepts decimal, hexadecimal `0x41`, binary `0b1010` and character `'a'` literals. Negative
+/// values wrap around, so `-1` is 255.
+fn immediate(pair: Pair<Rule>) -> Result<Immediate, Diagnostic> {
+    constant(pair, 1, false).map(|value| value as Immediate)
+}
+
+/// Parse an `immediate` rule for a variable `bytes` cells wide, like [`immediate`]
+///
+/// Negative values wrap around at the width of the variable, so `-1` is 65535 for two cells.
+/// Constants of `signed` variables must fit their two's complement range, -128 to 127.
+fn constant(pair: Pair<Rule>, bytes: u8, signed: bool) -> Result<Constant, Diagnostic> {
+    let literal = pair.as_str();
+    let error = |error: ParseError| Diagnostic::new(error, Span::from_pest(pair.as_span()));
+
+    let max = (1u64 << (8 * bytes as u32)) - 1;
+    let (min, max) = match signed {
+        true => (-(max as i64 + 1) / 2, max / 2),
+        false => (-(max as i64), max),
+    };
+    let out_of_range = || {
+        error(ParseError::OutOfRange {
+            literal: literal.to_string(),
+            min,
+            max,
+        })
+    };
+
+    let value = if let Some(inner) = literal.strip_prefix('\'') {
+        let invalid = |reason: &str| {
+            error(ParseError::InvalidCharacter {
+                literal: literal.to_string(),
+                reason: reason.to_string(),
+            })
+        };
+        let text = unescape(&inner[..inner.len() - 1]).map_err(|e| invalid(&e))?;
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => c as i64,
+            _ => return Err(invalid("must be exactly one character")),
+        }
+    } else {
+        let (negative, digits) = match literal.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, literal),
+        };
+        let parsed = if let Some(hex) = digits.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16)
+        } else if let Some(binary) = digits.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2)
+        } else {
+            digits.parse()
+        };
+        let value = parsed.map_err(|e| match e.kind() {
+            IntErrorKind::PosOverflow => out_of_range(),
+            _ => error(ParseError::InvalidNumber {
+                number: literal.to_string(),
+                reason: e.to_string(),
+            }),
+        })?;
+        if negative {
+            -value
+        } else {
+            value
+        }
+    };
+
+    if value < min || value > max as i64 {
+        return Err(out_of_range());
+    }
+    match value {
+        ..0 => Ok((value + (1i64 << (8 * bytes as u32))) as Constant),
+        _ => Ok(value as Constant),
+    }
+}
+
+/// Parse the comparison of an `if_compare` or `while_compare` rule
+fn comparison(pair: Pair<Rule>) -> Comparison {
+    let comparison = pair.into_inner().next().unwrap();
+    comparison.as_str().parse().unwrap()
+}
+
+/// Parse a `string_literal` rule, removing the quotes and escapes
+fn string(pair: Pair<Rule>) -> Result<String, Diagnostic> {
+    let literal = pair.as_str();
+    unescape(&literal[1..literal.len() - 1]).map_err(|reason| {
+        let error = ParseError::InvalidString {
+            literal: literal.to_string(),
+            reason,
+        };
+        Diagnostic::new(error, Span::from_pest(pair.as_span()))
+    })
 }
 
+/// Replace escape sequences like `\n`, `\x41` and `\u{2192}` with the characters they stand for
+///
+/// `\xNN` is the character with the code point `NN`, so `\xe9` is `é`.
+fn unescape(text: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '\'' | '"')) => c,
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 => byte as char,
+                    _ => return Err(format!("`\\x{hex}` needs two hexadecimal digits")),
+                }
+            }
+            Some('u') => {
+                let rest = chars.as_str();
+                let end = rest.find('}').map_or(rest.len(), |i| i + 1);
+                let code = &rest[..end];
+                chars = rest[end..].chars();
+                code.strip_prefix('{')
+                    .and_then(|code| code.strip_suffix('}'))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| {
+                        format!("`\\u{code}` is not a unicode escape like `\\u{{e9}}`")
+                    })?
+            }
+            Some(c) => return Err(format!("unknown escape `\\{c}`")),
+            None => return Err("escape at the end".to_string()),
+        });
+    }
+
+    Ok(out)
+}
+
+#[allow(dead_code)]
 pub fn parse(input: &str) -> Result<Vec<Instruction>> {
-    let pairs = LirParser::parse(Rule::program, input)?;
+    Ok(parse_with_spans(input)?.0)
+}
+
+/// Parse source code, also returning the location of every instruction
+///
+/// Errors are returned as [`Diagnostics`]. A syntax error stops parsing, other errors are
+/// collected for all instructions.
+pub fn parse_with_spans(input: &str) -> Result<(Vec<Instruction>, Vec<Span>)> {
+    let pairs = LirParser::parse(Rule::program, input)
+        .map_err(|e| Diagnostics::from(pest_diagnostic(e, input)))?;
     let mut instructions = Vec::new();
+    let mut spans = Vec::new();
+    let mut errors = Vec::new();
+    // Cells of every `var16` and `var32` and every `svar` seen so far, for the range of their
+    // constants
+    let mut widths = HashMap::new();
+    let mut signed = HashSet::new();
 
     for pair in pairs {
         match pair.as_rule() {
             Rule::program => {
                 for instruction in pair.into_inner() {
-                    if let Some(inst) = parse_instruction(instruction)? {
-                        instructions.push(inst);
+                    let span = Span::from_pest(instruction.as_span());
+                    match parse_instruction(instruction, &mut widths, &mut signed) {
+                        Ok(Some(inst)) => {
+                            instructions.push(inst);
+                            spans.push(span);
+                        }
+                        Ok(None) => {}
+                        Err(e) => errors.push(e),
                     }
                 }
             }
@@ -34,10 +297,22 @@ pub fn parse(input: &str) -> Result<Vec<Instruction>> {
         }
     }
 
-    Ok(instructions)
+    if !errors.is_empty() {
+        return Err(Diagnostics(errors).into());
+    }
+    Ok((instructions, spans))
 }
 
-fn parse_instruction(pair: Pair<Rule>) -> Result<Option<Instruction>> {
+fn parse_instruction(
+    pair: Pair<Rule>,
+    widths: &mut HashMap<String, u8>,
+    signed: &mut HashSet<String>,
+) -> Result<Option<Instruction>, Diagnostic> {
+    // Parse the constant of the variable `var`
+    let constant = |var: &str, pair: Pair<Rule>| {
+        constant(pair, *widths.get(var).unwrap_or(&1), signed.contains(var))
+    };
+
     match pair.as_rule() {
         Rule::instruction => {
             let inner = pair.into_inner().next().unwrap();
@@ -59,25 +334,37 @@ fn parse_instruction(pair: Pair<Rule>) -> Result<Option<Instruction>> {
                 Rule::inc_by_instr => {
                     let mut inner = inner.into_inner();
                     let var = inner.next().unwrap().as_str().to_string();
-                    let val = inner.next().unwrap().as_str().parse()?;
+                    let val = constant(&var, inner.next().unwrap())?;
                     Instruction::IncBy(var, val)
                 }
                 Rule::dec_by_instr => {
                     let mut inner = inner.into_inner();
                     let var = inner.next().unwrap().as_str().to_string();
-                    let val = inner.next().unwrap().as_str().parse()?;
+                    let val = constant(&var, inner.next().unwrap())?;
                     Instruction::DecBy(var, val)
                 }
                 Rule::set_instr => {
                     let mut inner = inner.into_inner();
                     let var = inner.next().unwrap().as_str().to_string();
-                    let val = inner.next().unwrap().as_str().parse()?;
+                    let val = constant(&var, inner.next().unwrap())?;
                     Instruction::Set(var, val)
                 }
                 Rule::read_instr => {
                     let var = inner.into_inner().next().unwrap().as_str().to_string();
                     Instruction::Read(var)
                 }
+                Rule::read_eof_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let eof = inner.next().unwrap().as_str().to_string();
+                    Instruction::ReadEof { a, eof }
+                }
+                Rule::readn_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let flag = inner.next().map(|flag| flag.as_str().to_string());
+                    Instruction::ReadN { a, flag }
+                }
                 Rule::print_instr => {
                     let var = inner.into_inner().next().unwrap().as_str().to_string();
                     Instruction::Print(var)
@@ -86,19 +373,41 @@ fn parse_instruction(pair: Pair<Rule>) -> Result<Option<Instruction>> {
                     let var = inner.into_inner().next().unwrap().as_str().to_string();
                     Instruction::PrintC(var)
                 }
+                Rule::printc_padded_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let width = immediate(inner.next().unwrap())?;
+                    let pad = inner.next().map(immediate).transpose()?.unwrap_or(b' ');
+                    Instruction::PrintCPadded { a, width, pad }
+                }
+                Rule::printx_instr => {
+                    let var = inner.into_inner().next().unwrap().as_str().to_string();
+                    Instruction::PrintX(var)
+                }
+                Rule::printb_instr => {
+                    let var = inner.into_inner().next().unwrap().as_str().to_string();
+                    Instruction::PrintB(var)
+                }
                 Rule::prints_instr => {
-                    let msg = inner.into_inner().next().unwrap().as_str();
-                    // Remove quotes from string literal
-                    let msg = msg[1..msg.len() - 1].to_string();
+                    let msg = string(inner.into_inner().next().unwrap())?;
                     Instruction::PrintS(msg)
                 }
                 Rule::match_instr => {
                     let mut inner = inner.into_inner();
                     let var = inner.next().unwrap().as_str().to_string();
-                    let values = inner.map(|v| v.as_str().parse::<u8>().unwrap()).collect();
+                    let values = inner.map(immediate).collect::<Result<_, _>>()?;
                     Instruction::Match(var, values)
                 }
                 Rule::case_instr => Instruction::Case {},
+                Rule::else_instr => Instruction::Else,
+                Rule::break_instr => {
+                    let depth = inner.into_inner().next().map(immediate).transpose()?;
+                    Instruction::Break(depth.unwrap_or(1))
+                }
+                Rule::continue_instr => {
+                    let depth = inner.into_inner().next().map(immediate).transpose()?;
+                    Instruction::Continue(depth.unwrap_or(1))
+                }
                 Rule::add_instr => {
                     let mut inner = inner.into_inner();
                     let a = inner.next().unwrap().as_str().to_string();
@@ -130,6 +439,101 @@ fn parse_instruction(pair: Pair<Rule>) -> Result<Option<Instruction>> {
                         quotient: q,
                     }
                 }
+                Rule::and_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = inner.next().unwrap().as_str().to_string();
+                    Instruction::And { a, b }
+                }
+                Rule::or_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = inner.next().unwrap().as_str().to_string();
+                    Instruction::Or { a, b }
+                }
+                Rule::xor_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = inner.next().unwrap().as_str().to_string();
+                    Instruction::Xor { a, b }
+                }
+                Rule::not_instr => {
+                    let var = inner.into_inner().next().unwrap().as_str().to_string();
+                    Instruction::Not(var)
+                }
+                Rule::shl_instr => {
+                    let mut inner = inner.into_inner();
+                    let var = inner.next().unwrap().as_str().to_string();
+                    let bits = immediate(inner.next().unwrap())?;
+                    Instruction::Shl(var, bits)
+                }
+                Rule::shr_instr => {
+                    let mut inner = inner.into_inner();
+                    let var = inner.next().unwrap().as_str().to_string();
+                    let bits = immediate(inner.next().unwrap())?;
+                    Instruction::Shr(var, bits)
+                }
+                Rule::land_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = inner.next().unwrap().as_str().to_string();
+                    Instruction::LogicalAnd { a, b }
+                }
+                Rule::lor_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = inner.next().unwrap().as_str().to_string();
+                    Instruction::LogicalOr { a, b }
+                }
+                Rule::lnot_instr => {
+                    let var = inner.into_inner().next().unwrap().as_str().to_string();
+                    Instruction::LogicalNot(var)
+                }
+                Rule::bool_instr => {
+                    let var = inner.into_inner().next().unwrap().as_str().to_string();
+                    Instruction::Bool(var)
+                }
+                Rule::wide_instr => {
+                    let mut inner = inner.into_inner();
+                    let bytes = match inner.next().unwrap().as_str() {
+                        "var16" => 2,
+                        _ => 4,
+                    };
+                    let name = inner.next().unwrap().as_str().to_string();
+                    widths.insert(name.clone(), bytes);
+                    Instruction::Wide { name, bytes }
+                }
+                Rule::svar_instr => {
+                    let name = inner.into_inner().next().unwrap().as_str().to_string();
+                    signed.insert(name.clone());
+                    Instruction::Signed(name)
+                }
+                Rule::cast_instr => {
+                    let mut inner = inner.into_inner();
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = inner.next().unwrap().as_str().to_string();
+                    Instruction::Cast { a, b }
+                }
+                Rule::array_instr => {
+                    let mut inner = inner.into_inner();
+                    let name = inner.next().unwrap().as_str().to_string();
+                    let len = immediate(inner.next().unwrap())?;
+                    Instruction::Array { name, len }
+                }
+                Rule::load_instr => {
+                    let mut inner = inner.into_inner();
+                    let arr = inner.next().unwrap().as_str().to_string();
+                    let idx = inner.next().unwrap().as_str().to_string();
+                    let dst = inner.next().unwrap().as_str().to_string();
+                    Instruction::Load { arr, idx, dst }
+                }
+                Rule::store_instr => {
+                    let mut inner = inner.into_inner();
+                    let arr = inner.next().unwrap().as_str().to_string();
+                    let idx = inner.next().unwrap().as_str().to_string();
+                    let src = inner.next().unwrap().as_str().to_string();
+                    Instruction::Store { arr, idx, src }
+                }
                 Rule::push_instr => {
                     let var = inner.into_inner().next().unwrap().as_str().to_string();
                     Instruction::Push(var)
@@ -147,7 +551,7 @@ fn parse_instruction(pair: Pair<Rule>) -> Result<Option<Instruction>> {
                 Rule::if_equal_const_instr => {
                     let mut inner = inner.into_inner();
                     let a = inner.next().unwrap().as_str().to_string();
-                    let b = inner.next().unwrap().as_str().parse()?;
+                    let b = constant(&a, inner.next().unwrap())?;
                     Instruction::IfEqualConst { a, b }
                 }
                 Rule::if_not_equal_instr => {
@@ -159,9 +563,37 @@ fn parse_instruction(pair: Pair<Rule>) -> Result<Option<Instruction>> {
                 Rule::if_not_equal_const_instr => {
                     let mut inner = inner.into_inner();
                     let a = inner.next().unwrap().as_str().to_string();
-                    let b = inner.next().unwrap().as_str().parse()?;
+                    let b = constant(&a, inner.next().unwrap())?;
                     Instruction::IfNotEqualConst { a, b }
                 }
+                Rule::if_compare_instr => {
+                    let mut inner = inner.into_inner();
+                    let op = comparison(inner.next().unwrap());
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = inner.next().unwrap().as_str().to_string();
+                    Instruction::IfCompare { op, a, b }
+                }
+                Rule::if_compare_const_instr => {
+                    let mut inner = inner.into_inner();
+                    let op = comparison(inner.next().unwrap());
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = constant(&a, inner.next().unwrap())?;
+                    Instruction::IfCompareConst { op, a, b }
+                }
+                Rule::while_compare_instr => {
+                    let mut inner = inner.into_inner();
+                    let op = comparison(inner.next().unwrap());
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = inner.next().unwrap().as_str().to_string();
+                    Instruction::WhileCompare { op, a, b }
+                }
+                Rule::while_compare_const_instr => {
+                    let mut inner = inner.into_inner();
+                    let op = comparison(inner.next().unwrap());
+                    let a = inner.next().unwrap().as_str().to_string();
+                    let b = constant(&a, inner.next().unwrap())?;
+                    Instruction::WhileCompareConst { op, a, b }
+                }
                 Rule::until_equal_instr => {
                     let mut inner = inner.into_inner();
                     let a = inner.next().unwrap().as_str().to_string();
@@ -172,6 +604,14 @@ fn parse_instruction(pair: Pair<Rule>) -> Result<Option<Instruction>> {
                     let var = inner.into_inner().next().unwrap().as_str().to_string();
                     Instruction::WhileNotZero(var)
                 }
+                Rule::if_not_zero_instr => {
+                    let var = inner.into_inner().next().unwrap().as_str().to_string();
+                    Instruction::IfNotZero(var)
+                }
+                Rule::if_zero_instr => {
+                    let var = inner.into_inner().next().unwrap().as_str().to_string();
+                    Instruction::IfZero(var)
+                }
                 Rule::compare_instr => {
                     let mut inner = inner.into_inner();
                     let a = inner.next().unwrap().as_str().to_string();
@@ -180,16 +620,19 @@ fn parse_instruction(pair: Pair<Rule>) -> Result<Option<Instruction>> {
                     Instruction::Compare { a, b, res }
                 }
                 Rule::raw_instr => {
-                    let raw = inner.into_inner().next().unwrap().as_str();
-                    // Remove quotes from string literal
-                    let raw = raw[1..raw.len() - 1].to_string();
+                    let raw = string(inner.into_inner().next().unwrap())?;
                     Instruction::Raw(raw)
                 }
                 Rule::end_instr => Instruction::End,
-                _ => return Err(ParseError::InvalidInstruction(inner.as_str().to_string()).into()),
+                _ => return Err(invalid_instruction(inner)),
             }))
         }
         Rule::EOI => Ok(None),
-        _ => Err(ParseError::InvalidInstruction(pair.as_str().to_string()).into()),
+        _ => Err(invalid_instruction(pair)),
     }
 }
+
+fn invalid_instruction(pair: Pair<Rule>) -> Diagnostic {
+    let error = ParseError::InvalidInstruction(pair.as_str().to_string());
+    Diagnostic::new(error, Span::from_pest(pair.as_span()))
+}
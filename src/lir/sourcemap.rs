@@ -0,0 +1,132 @@
+use crate::lir::parser::Span;
+use std::ops::Range;
+
+/// Where the code of a single instruction ended up
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceMapEntry {
+    /// Index of the instruction in the program
+    pub instruction: usize,
+    /// Line of the instruction, starting at 1
+    pub line: usize,
+    /// Column of the instruction, starting at 1
+    pub column: usize,
+    /// Source code of the instruction
+    pub source: String,
+    /// Range of the generated brainfuck
+    pub bf: Range<usize>,
+    /// Range of the generated brainfuck after optimization
+    pub optimized: Option<Range<usize>>,
+}
+
+impl SourceMapEntry {
+    /// Range in the code that is actually emitted, optimized if it was optimized
+    pub fn output(&self) -> &Range<usize> {
+        self.optimized.as_ref().unwrap_or(&self.bf)
+    }
+}
+
+/// Maps generated brainfuck back to LIR instructions
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceMap {
+    pub entries: Vec<SourceMapEntry>,
+}
+
+impl SourceMap {
+    /// Build a source map from the spans of the parser and the ranges of codegen and optimizer
+    pub fn new(
+        source: &str,
+        spans: &[Span],
+        bf: Vec<Range<usize>>,
+        optimized: Option<Vec<Range<usize>>>,
+    ) -> Self {
+        debug_assert_eq!(spans.len(), bf.len());
+
+        let mut optimized = optimized.map(|o| o.into_iter());
+        let entries = spans
+            .iter()
+            .zip(bf)
+            .enumerate()
+            .map(|(instruction, (span, bf))| SourceMapEntry {
+                instruction,
+                line: span.line,
+                column: span.column,
+                source: source[span.start..span.end].to_string(),
+                bf,
+                optimized: optimized.as_mut().and_then(|o| o.next()),
+            })
+            .collect();
+
+        Self { entries }
+    }
+
+    /// Find the instruction that emitted the character at `position` of the emitted code
+    #[allow(dead_code)]
+    pub fn lookup(&self, position: usize) -> Option<&SourceMapEntry> {
+        self.lookup_index(position).map(|i| &self.entries[i])
+    }
+
+    /// Like [`SourceMap::lookup`], but returns the index of the entry
+    pub fn lookup_index(&self, position: usize) -> Option<usize> {
+        // Entries are in the same order as their code
+        let i = self.entries.partition_point(|e| e.output().end <= position);
+        self.entries
+            .get(i)
+            .filter(|e| e.output().contains(&position))
+            .map(|_| i)
+    }
+
+    /// Find the last instruction that starts at or before `position` of the emitted code
+    ///
+    /// Unlike [`SourceMap::lookup`] this also finds the instruction before a separator, which is
+    /// the one responsible for a failed temporary variable check.
+    pub fn preceding(&self, position: usize) -> Option<&SourceMapEntry> {
+        let i = self
+            .entries
+            .partition_point(|e| e.output().start <= position);
+        i.checked_sub(1).map(|i| &self.entries[i])
+    }
+
+    /// Serialize into JSON
+    pub fn to_json(&self, file: &str) -> String {
+        let range = |r: &Range<usize>| format!("[{}, {}]", r.start, r.end);
+
+        let mappings = self
+            .entries
+            .iter()
+            .map(|e| {
+                format!(
+                    "    {{\"instruction\": {}, \"line\": {}, \"column\": {}, \"source\": {}, \"bf\": {}, \"optimized\": {}}}",
+                    e.instruction,
+                    e.line,
+                    e.column,
+                    json_string(&e.source),
+                    range(&e.bf),
+                    e.optimized.as_ref().map_or("null".to_string(), range)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",\n");
+
+        format!(
+            "{{\n  \"version\": 1,\n  \"file\": {},\n  \"mappings\": [\n{mappings}\n  ]\n}}\n",
+            json_string(file)
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,9 +1,14 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use crate::bf;
+    use crate::bf::interpreter::{Interpreter, RuntimeError};
     use crate::lir::codegen::Codegen;
-    use crate::lir::instruction::Instruction;
+    use crate::lir::diagnostic::{Diagnostic, Diagnostics};
     use crate::lir::instruction::Instruction::*;
+    use crate::lir::instruction::{Constant, Instruction};
+    use crate::lir::sourcemap::SourceMap;
+    use crate::pipeline::{OptLevel, Options};
     use std::fs::File;
     use std::io::Read;
     use std::path::Path;
@@ -20,7 +25,7 @@ mod tests {
                 let mut stdin = input.as_bytes();
                 let mut stdout = Vec::new();
                 let interpret = bf::interpreter::Interpreter::new();
-                interpret.run(&bf, &mut stdin, &mut stdout);
+                interpret.run(&bf, &mut stdin, &mut stdout).unwrap();
             }
 
             let bf = bf::optim::remove_non_brainfuck(bf); // To remove instruction separators
@@ -43,8 +48,8 @@ mod tests {
             Read("a".to_string()),
             Inc("a".to_string()),
             Print("a".to_string()),
-            // Instruction::Set("b".to_string(), b'Z'), // Set should be equivalent except for a [-]
-            IncBy("b".to_string(), b'Z'),
+            // Instruction::Set("b".to_string(), b'Z'.into()), // Set should be equivalent except for a [-]
+            IncBy("b".to_string(), b'Z'.into()),
             Print("b".to_string()),
         ];
         assert_eq_bf(code, "[-],+.>++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.");
@@ -110,7 +115,7 @@ mod tests {
         // If input is not A, print it twice
         let code = vec![
             Read("a".to_string()),
-            Set("b".to_string(), b'A'),
+            Set("b".to_string(), b'A'.into()),
             IfNotEqual {
                 a: "a".to_string(),
                 b: "b".to_string(),
@@ -126,7 +131,7 @@ mod tests {
 
         let code = vec![
             Read("a".to_string()),
-            Set("b".to_string(), b'A'),
+            Set("b".to_string(), b'A'.into()),
             UntilEqual {
                 a: "a".to_string(),
                 b: "b".to_string(),
@@ -143,12 +148,12 @@ mod tests {
         // If input is A, set it to B and print twice, else print it once
         let code = vec![
             Read("a".to_string()),
-            Set("b".to_string(), b'A'),
+            Set("b".to_string(), b'A'.into()),
             IfEqual {
                 a: "a".to_string(),
                 b: "b".to_string(),
             },
-            Set("a".to_string(), b'B'),
+            Set("a".to_string(), b'B'.into()),
             Print("a".to_string()),
             End,
             Print("a".to_string()),
@@ -169,9 +174,9 @@ mod tests {
             Read("a".to_string()),
             IfEqualConst {
                 a: "a".to_string(),
-                b: b'A',
+                b: b'A'.into(),
             },
-            Set("a".to_string(), b'B'),
+            Set("a".to_string(), b'B'.into()),
             Print("a".to_string()),
             End,
             Print("a".to_string()),
@@ -210,7 +215,7 @@ mod tests {
         ];
         assert_eq_bf(
             code,
-          "[-],>[-],>[-]<[-<<+>->]<<[->>+<<]><<<[-]>>>[-<+<<+>>>]<[->+<]<<[[-]>>>>[-<<+>+>]<<[->>+<<]><<[-]>[-]+[<+>>>-<-<<<[-]+<[-]>>>>[<<<[-]<[-]>>>>[-<<<<+>>>>]]<<<<[->>>>+<<<<]>>>><<<[[-]>>>>>[-]+<<<[-]<<[-]][-]+<[-]>>>>>[<<<<[-]<[-]>>>>>[-<<<<<+>>>>>]]<<<<<[->>>>>+<<<<<]>>>>><<<<[[-]>>>>>[-]++<<<[-]<<[-]]>>]<[->>+>+<<<]>>>[-<<+>->]<<[->>+<<]><<<]>>>>[-<<+>+>]<<[->>+<<]><<<[-]+<[-]>>>>>>[<<<<<[-]<[-]>>>>>>[-<<<<<<+>>>>>>]]<<<<<<[->>>>>>+<<<<<<]>>>>>><<<<<[[-]>>[-]+++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<[-]][-]+<[-]>>>>>>-[<<<<<[-]<[-]>>>>>>[-<<<<<<+>>>>>>]]<<<<<<[->>>>>>+<<<<<<]>>>>>>+<<<<<[[-]>>[-]++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<[-]][-]+<[-]>>>>>>--[<<<<<[-]<[-]>>>>>>[-<<<<<<+>>>>>>]]<<<<<<[->>>>>>+<<<<<<]>>>>>>++<<<<<[[-]>>[-]++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<[-]]"
+            "[-],>[-],>[-]<[-<<+>->]<<[->>+<<]><<<[-]>>>[-<+<<+>>>]<[->+<]<<[[-]>>>>[-<<+>+>]<<[->>+<<]><<[-]>[-]+<<[-]+<[-]>>>>[<<<[-]<[-]>>>>[-<<<<+>>>>]]<<<<[->>>>+<<<<]>>>><<<[[-]>>>>>[-]+<<<[-]<<[-]][-]+<[-]>>>>>[<<<<[-]<[-]>>>>>[-<<<<<+>>>>>]]<<<<<[->>>>>+<<<<<]>>>>><<<<[[-]>>>>>[-]++<<<[-]<<[-]]>>[<+>>>-<-<<<[-]+<[-]>>>>[<<<[-]<[-]>>>>[-<<<<+>>>>]]<<<<[->>>>+<<<<]>>>><<<[[-]>>>>>[-]+<<<[-]<<[-]][-]+<[-]>>>>>[<<<<[-]<[-]>>>>>[-<<<<<+>>>>>]]<<<<<[->>>>>+<<<<<]>>>>><<<<[[-]>>>>>[-]++<<<[-]<<[-]]>>]<[->>+>+<<<]>>>[-<<+>->]<<[->>+<<]><<<]>>>>[-<<+>+>]<<[->>+<<]><<<[-]+<[-]>>>>>>[<<<<<[-]<[-]>>>>>>[-<<<<<<+>>>>>>]]<<<<<<[->>>>>>+<<<<<<]>>>>>><<<<<[[-]>>[-]+++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<[-]][-]+<[-]>>>>>>-[<<<<<[-]<[-]>>>>>>[-<<<<<<+>>>>>>]]<<<<<<[->>>>>>+<<<<<<]>>>>>>+<<<<<[[-]>>[-]++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<[-]][-]+<[-]>>>>>>--[<<<<<[-]<[-]>>>>>>[-<<<<<<+>>>>>>]]<<<<<<[->>>>>>+<<<<<<]>>>>>>++<<<<<[[-]>>[-]++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]<<[-]]"
         );
 
         // Should print D
@@ -241,7 +246,7 @@ mod tests {
             Print("q".to_string()),
             Print("r".to_string()),
         ];
-        assert_eq_bf(code, "[-]+++++++++>[-]++<<<<<<<<<<<[-]>>>>>>>>>>[-<+<<<<<<<<<+>>>>>>>>>>]<[->+<]<<<<<<<<<>[-]>>>>>>>>>>[-<<+<<<<<<<<+>>>>>>>>>>]<<[->>+<<]<<<<<<<<>>>>>>>>>>>[-]>[-]<<<<<<<<<<<[-]+[>[-]>>>>>>>>[-<<+>->]<<[->>+<<]><<<[-]>>>[-<+<<+>>>]<[->+<]<<[[-]>>>>[-<<+>+>]<<[->>+<<]><<[-]>[-]+[<+>>>-<-<<<[-]+<[-]>>>>[<<<[-]<[-]>>>>[-<<<<+>>>>]]<<<<[->>>>+<<<<]>>>><<<[[-]<<<<[-]+>>>>>>[-]<<[-]][-]+<[-]>>>>>[<<<<[-]<[-]>>>>>[-<<<<<+>>>>>]]<<<<<[->>>>>+<<<<<]>>>>><<<<[[-]<<<<[-]++>>>>>>[-]<<[-]]>>]<[->>+>+<<<]>>>[-<<+>->]<<[->>+<<]><<<]>>>>[-<<+>+>]<<[->>+<<]><<<<<<<->>>>[-]<<<<[->>>>>>+<<+<<<<]>>>>>>[-<<<<<<+>>>>>>]<<<<<<+>>>>[[-]>>>>[-<<+>->]<<[->>+<<]>>>>+<<<<<<][-]<<<<-->>>>[-]<<<<[->>>>>>+<<+<<<<]>>>>>>[-<<<<<<+>>>>>>]<<<<<<++>>>>[[-]<<<<<[-]>>>>>][-]<<<<<]>[-]>>>>>>>>>[-]<<[->>+<<][-]<<<<<<<<<<[->>>>>>>>>>+<<<<<<<<<<]>>>>>>>>>>>[-]<<<<<<<<<<[->>>>>>>>>>+<<<<<<<<<<]>>>>>>>>>>>>.<.");
+        assert_eq_bf(code, "[-]+++++++++>[-]++<<<<<<<<<<<[-]>>>>>>>>>>[-<+<<<<<<<<<+>>>>>>>>>>]<[->+<]<<<<<<<<<>[-]>>>>>>>>>>[-<<+<<<<<<<<+>>>>>>>>>>]<<[->>+<<]<<<<<<<<>>>>>>>>>>>[-]>[-]<<<<<<<<<<<[-]+[>[-]>>>>>>>>[-<<+>->]<<[->>+<<]><<<[-]>>>[-<+<<+>>>]<[->+<]<<[[-]>>>>[-<<+>+>]<<[->>+<<]><<[-]>[-]+<<[-]+<[-]>>>>[<<<[-]<[-]>>>>[-<<<<+>>>>]]<<<<[->>>>+<<<<]>>>><<<[[-]<<<<[-]+>>>>>>[-]<<[-]][-]+<[-]>>>>>[<<<<[-]<[-]>>>>>[-<<<<<+>>>>>]]<<<<<[->>>>>+<<<<<]>>>>><<<<[[-]<<<<[-]++>>>>>>[-]<<[-]]>>[<+>>>-<-<<<[-]+<[-]>>>>[<<<[-]<[-]>>>>[-<<<<+>>>>]]<<<<[->>>>+<<<<]>>>><<<[[-]<<<<[-]+>>>>>>[-]<<[-]][-]+<[-]>>>>>[<<<<[-]<[-]>>>>>[-<<<<<+>>>>>]]<<<<<[->>>>>+<<<<<]>>>>><<<<[[-]<<<<[-]++>>>>>>[-]<<[-]]>>]<[->>+>+<<<]>>>[-<<+>->]<<[->>+<<]><<<]>>>>[-<<+>+>]<<[->>+<<]><<<<<<<->>>>[-]<<<<[->>>>>>+<<+<<<<]>>>>>>[-<<<<<<+>>>>>>]<<<<<<+>>>>[[-]>>>>[-<<+>->]<<[->>+<<]>>>>+<<<<<<][-]<<<<-->>>>[-]<<<<[->>>>>>+<<+<<<<]>>>>>>[-<<<<<<+>>>>>>]<<<<<<++>>>>[[-]<<<<<[-]>>>>>][-]<<<<<]>[-]>>>>>>>>>[-]<<[->>+<<][-]<<<<<<<<<<[->>>>>>>>>>+<<<<<<<<<<]>>>>>>>>>>>[-]<<<<<<<<<<[->>>>>>>>>>+<<<<<<<<<<]>>>>>>>>>>>>.<.");
 
         let code = vec![
             // 9 2 0
@@ -271,10 +276,10 @@ mod tests {
 
         let code = vec![
             Read("a".to_string()),
-            Set("b".to_string(), b'-'),
+            Set("b".to_string(), b'-'.into()),
             Match("a".to_string(), vec![b'a', b'b']),
             PrintS("C".to_string()), // default
-            Case(),                    // b
+            Case(),                  // b
             PrintS("B".to_string()),
             Case(), // a
             PrintS("A".to_string()),
@@ -299,6 +304,1064 @@ mod tests {
         // assert_eq!(bf, "<<[-]>>[-<+<+>>]<[->+<]<>[-]+<[-[-[-[[-]>-#<]>[-#]<]>[-#]<]>[-#]<]>[-#]#");
     }
 
+    #[test]
+    fn fold_constants() {
+        let a = || "a".to_string();
+        let folded = crate::lir::optim::fold_constants(vec![
+            Set(a(), 5),
+            Inc(a()),
+            DecBy(a(), 10),
+            Print(a()),
+            Inc(a()),
+            Dec(a()),
+            IncBy("b".to_string(), 200),
+            IncBy("b".to_string(), 100),
+            Set(a(), 250),
+            IncBy(a(), 10),
+        ]);
+        assert_eq!(
+            folded,
+            vec![
+                Set(a(), 6),
+                Raw(String::new()),
+                // Would go below zero
+                DecBy(a(), 10),
+                Print(a()),
+                IncBy(a(), 0),
+                Raw(String::new()),
+                // Would go above 255
+                IncBy("b".to_string(), 200),
+                IncBy("b".to_string(), 100),
+                Set(a(), 250),
+                IncBy(a(), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn constants() {
+        for n in [0u8, 1, 7, 17, 100, 200, 255] {
+            let code = format!(
+                "set a {n}\nprint a\ninc_by a {n}\nprint a\ndec_by a 255\nprint a\nprints \"~ a\"\n"
+            );
+            let expected = [
+                n,
+                n.wrapping_add(n),
+                n.wrapping_add(n).wrapping_add(1),
+                b'~',
+                b' ',
+                b'a',
+            ];
+
+            for level in [OptLevel::O0, OptLevel::O2, OptLevel::O3, OptLevel::Os] {
+                let bf = crate::pipeline::compile(&code, level).unwrap().code;
+                let mut output = Vec::new();
+                bf::interpreter::Interpreter::new()
+                    .run(&bf, &mut "".as_bytes(), &mut output)
+                    .unwrap();
+                assert_eq!(output, expected, "{level:?} {n}");
+            }
+        }
+
+        // Large constants use a multiplication loop unless speed is preferred
+        let set = vec![Set("a".to_string(), 200)];
+        let looped = Codegen::new(set.clone())
+            .with_opt_level(OptLevel::Os)
+            .codegen()
+            .unwrap();
+        let literal = Codegen::new(set)
+            .with_opt_level(OptLevel::O3)
+            .codegen()
+            .unwrap();
+        assert_eq!(literal.matches('+').count(), 200);
+        assert!(looped.len() < literal.len(), "{looped}");
+    }
+
+    #[test]
+    fn diagnostics() {
+        fn diagnostics(source: &str) -> Diagnostics {
+            let error = crate::pipeline::compile(source, OptLevel::O2).unwrap_err();
+            error.downcast::<Diagnostics>().unwrap()
+        }
+        fn diagnostic(source: &str) -> Diagnostic {
+            diagnostics(source).0.remove(0)
+        }
+
+        let source = "set x 1\nwhile_nz x\n    copy x y\n    print z\nend\n";
+        assert_eq!(
+            diagnostic(source).render("test.lir", source),
+            "error: Variable z must be assigned before use
+ --> test.lir:4:11
+  |
+4 |     print z
+  |           ^"
+        );
+
+        let source = "set x 1\nfoo x\n";
+        let d = diagnostic(source);
+        assert_eq!((d.span.line, d.span.column), (2, 1));
+        assert!(d.message.contains("instruction"), "{}", d.message);
+
+        let d = diagnostic("set x 300");
+        assert_eq!(
+            d.message,
+            "`300` is out of range, values must be between -255 and 255"
+        );
+        assert_eq!((d.span.start, d.span.end, d.span.column), (6, 9, 7));
+
+        let d = diagnostic("set x 1\nmatch x 1 256\nend");
+        assert_eq!((d.span.line, d.span.column), (2, 11));
+
+        let d = diagnostic("set x 10\nmatch x 'Z' '\\n'\ncase\nend");
+        assert_eq!((d.span.line, d.span.column), (2, 1));
+        assert_eq!(
+            d.message,
+            "The constants of a `match` must be in ascending order"
+        );
+
+        // Blocks point at the block that is never closed or the extra `end`
+        let d = diagnostic("set x 1\nif_eq x 1\nwhile_nz x\nend\n");
+        assert_eq!((d.span.line, d.span.column), (2, 1));
+        assert_eq!(d.message, "Block is never closed with `end`");
+
+        // Every error is reported, in the order of the source
+        let source = "set x 1\nprint y\ncase\nend\nif_eq x 1\nprint y\nset y 1\nprint y\n";
+        let all = diagnostics(source);
+        let found: Vec<_> = all
+            .0
+            .iter()
+            .map(|d| (d.span.line, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (2, "Variable y must be assigned before use"),
+                (3, "`case` outside of a `match`"),
+                (4, "`end` without a block to end"),
+                (5, "Block is never closed with `end`"),
+                (6, "Variable y must be assigned before use"),
+            ]
+        );
+        assert!(all
+            .render("test.lir", source)
+            .ends_with("\n\nerror: could not compile `test.lir` due to 5 previous errors"));
+        let numbers = diagnostics("set x 300\ninc_by x 256\n");
+        assert_eq!(numbers.0.len(), 2);
+
+        // The second `x` is the argument, not the instruction name
+        let d = diagnostic("copy  copy x");
+        assert_eq!((d.span.line, d.span.column), (1, 7));
+        assert_eq!(d.message, "Variable copy must be assigned before use");
+    }
+
+    #[test]
+    fn literals() {
+        fn constant(literal: &str) -> Result<Constant, String> {
+            match crate::lir::parser::parse(&format!("set x {literal}")) {
+                Ok(parsed) => match parsed[..] {
+                    [Instruction::Set(_, value)] => Ok(value),
+                    _ => unreachable!(),
+                },
+                Err(e) => Err(e.to_string()),
+            }
+        }
+
+        assert_eq!(constant("65"), Ok(65));
+        assert_eq!(constant("'a'"), Ok(97));
+        assert_eq!(constant("'\\n'"), Ok(10));
+        assert_eq!(constant("'\\''"), Ok(39));
+        assert_eq!(constant("'\\x7f'"), Ok(127));
+        assert_eq!(constant("0x41"), Ok(65));
+        assert_eq!(constant("0b1010"), Ok(10));
+        assert_eq!(constant("-1"), Ok(255));
+        assert_eq!(constant("-0x80"), Ok(128));
+        assert_eq!(constant("-255"), Ok(1));
+
+        assert!(constant("256").unwrap_err().contains("out of range"));
+        assert!(constant("-256").unwrap_err().contains("out of range"));
+        assert!(constant("99999999999999999999")
+            .unwrap_err()
+            .contains("out of range"));
+        assert!(constant("0xZZ").unwrap_err().contains("Invalid number"));
+        assert!(constant("'ab'").unwrap_err().contains("one character"));
+        assert!(constant("'\\q'").unwrap_err().contains("unknown escape"));
+
+        // Literals work everywhere an immediate does
+        let source = "set c 'b'\ninc_by c 0x01\ndec_by c -1\nif_eq c 'd'\nprints \"d\"\nend\nif_neq c 0b1\nmatch c 'a' 'd'\ncase\nprints \"!\"\ncase\nend\nend\n";
+        let code = crate::pipeline::compile(source, OptLevel::O2).unwrap().code;
+        let mut output = Vec::new();
+        Interpreter::new()
+            .run(&code, &mut "".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(output, b"d!");
+    }
+
+    #[test]
+    fn strings() {
+        let source =
+            "prints \"na\u{ef}ve \u{2192} \\\"ok\\\"\\n\"\nprints 'it\\'s \\x41\\u{1F600}\\\\'\n";
+        let code = crate::pipeline::compile(source, OptLevel::O2).unwrap().code;
+        let mut output = Vec::new();
+        Interpreter::new()
+            .run(&code, &mut "".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "na\u{ef}ve \u{2192} \"ok\"\nit's A\u{1F600}\\"
+        );
+
+        let parsed = crate::lir::parser::parse("raw \"+\\\".\"").unwrap();
+        assert_eq!(parsed, [Raw("+\".".to_string())]);
+
+        for bad in ["\\q", "\\x4", "\\u{110000}", "\\u{41"] {
+            let error = crate::lir::parser::parse(&format!("prints \"{bad}\""));
+            assert!(error.is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn numeric_printing() {
+        let source = r#"
+set a 0
+printc a
+prints " "
+set a 105
+printc a
+prints " "
+set a 10
+printc a
+prints " "
+set a 255
+printc a
+prints "|"
+set a 7
+printc a 3 '0'
+prints "|"
+printc a 4
+prints "|"
+set a 42
+printc a 1
+prints "|"
+printx a
+prints " "
+set a 255
+printx a
+prints " "
+set a 5
+printx a
+prints " "
+set a 10
+printb a
+"#;
+        for level in [OptLevel::O0, OptLevel::O2] {
+            let code = crate::pipeline::compile(source, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "0 105 10 255|007|   7|42|2a ff 05 00001010"
+            );
+        }
+    }
+
+    #[test]
+    fn read_numbers() {
+        let source = r#"
+readn a ok
+printc a
+prints ","
+printc ok
+prints " "
+readn b ok
+printc b
+prints ","
+printc ok
+prints " "
+readn c
+printc c
+prints " "
+readn d ok
+printc d
+prints ","
+printc ok
+"#;
+        let code = crate::pipeline::compile(source, OptLevel::O2).unwrap().code;
+        for (input, expected) in [
+            ("  42\n\t007x300 ", "42,1 7,1 44 0,0"),
+            ("12", "12,1 0,0 0 0,0"),
+            ("", "0,0 0,0 0 0,0"),
+        ] {
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut input.as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn else_blocks() {
+        // The then block changes `a`, so only one of the blocks may run
+        let source = r#"
+set a 5
+set b 5
+if_eq a 5
+    prints "eq5 "
+    set a 6
+else
+    prints "ne5 "
+end
+if_eq a 5
+    prints "eq5 "
+else
+    prints "ne5 "
+    if_neq a b
+        prints "a!=b "
+        if_eq a b
+            prints "bad"
+        else
+            prints "inner-else "
+        end
+    else
+        prints "bad"
+    end
+end
+if_neq a 6
+    prints "bad"
+else
+    prints "a=6 "
+end
+set b2 5
+if_eq b b2
+    prints "b=b2"
+else
+    prints "bad"
+end
+"#;
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let code = crate::pipeline::compile(source, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "eq5 ne5 a!=b inner-else a=6 b=b2"
+            );
+        }
+
+        let error = crate::pipeline::compile(
+            "set a 1\nelse\nif_eq a 1\nelse\nelse\nend\nwhile_nz a\nelse\nend\n",
+            OptLevel::O2,
+        )
+        .unwrap_err()
+        .downcast::<Diagnostics>()
+        .unwrap();
+        let lines: Vec<_> = error.0.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [2, 5, 8]);
+    }
+
+    #[test]
+    fn compare_zero() {
+        // `compare` used to count both variables down and only check them after decrementing,
+        // so a variable that starts at zero wrapped around and compared as the greater one
+        let source = r#"
+set z 0
+set y 0
+set a 5
+compare z a c
+printc c
+compare a z c
+printc c
+compare z y c
+printc c
+set b 5
+compare a b c
+printc c
+set a 1
+compare z a c
+printc c
+printc z
+printc a
+"#;
+        for level in [OptLevel::O0, OptLevel::O2] {
+            let code = crate::pipeline::compile(source, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), "1200101", "{level:?}");
+        }
+    }
+
+    #[test]
+    fn ordering_blocks() {
+        let source = r#"
+set a 3
+set b 7
+set z 0
+set m 255
+if_lt a b
+    prints "lt "
+else
+    prints "bad "
+end
+if_gt a b
+    prints "bad "
+else
+    prints "notgt "
+end
+if_le a 3
+    prints "le3 "
+end
+if_ge a 4
+    prints "bad "
+end
+if_ge m 255
+    prints "m255 "
+end
+if_lt z 1
+    prints "z<1 "
+end
+if_gt m z
+    prints "m>z "
+end
+set z2 0
+if_le z z2
+    prints "z<=z2 "
+end
+while_lt a b
+    printc a
+    inc a
+end
+prints " "
+while_ge b 5
+    printc b
+    dec b
+end
+prints " "
+set i 0
+while_le i 2
+    printc i
+    inc i
+end
+"#;
+        for level in [OptLevel::O0, OptLevel::O2] {
+            let code = crate::pipeline::compile(source, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "lt notgt le3 m255 z<1 m>z z<=z2 3456 765 012"
+            );
+        }
+    }
+
+    #[test]
+    fn arrays() {
+        // Elements next to each other, at both ends and read back with runtime indices
+        let source = r#"
+array xs 4
+array ys 2
+set i 0
+while_lt i 4
+    copy i v
+    inc_by v 'a'
+    store xs i v
+    inc i
+end
+set i 1
+set v 'z'
+store ys i v
+store xs i v
+set i 4
+while_nz i
+    dec i
+    load xs i c
+    print c
+end
+set i 1
+load ys i c
+print c
+set i 0
+load ys i c
+inc_by c '0'
+print c
+"#;
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            for sanitize in [false, true] {
+                let options = Options {
+                    sanitize,
+                    ..Default::default()
+                };
+                let code = crate::pipeline::compile_with(source, level, options)
+                    .unwrap()
+                    .code;
+                let mut output = Vec::new();
+                Interpreter::new()
+                    .run(&code, &mut "".as_bytes(), &mut output)
+                    .unwrap();
+                assert_eq!(String::from_utf8(output).unwrap(), "dczaz0", "{level:?}");
+            }
+        }
+
+        // Only stopped by sanitizer checks
+        let source = "array xs 2\nset i 2\nset v 1\nstore xs i v\nprints \"past\"\n";
+        for level in [OptLevel::O0, OptLevel::O2] {
+            let options = Options {
+                sanitize: true,
+                ..Default::default()
+            };
+            let code = crate::pipeline::compile_with(source, level, options)
+                .unwrap()
+                .code;
+            let mut output = Vec::new();
+            let error = Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap_err();
+            assert!(matches!(error, RuntimeError::IndexOutOfRange { .. }));
+            assert!(output.is_empty());
+        }
+
+        let error = crate::pipeline::compile(
+            "load xs i v\narray xs 2\nset xs 1\narray xs 3\narray ys 0\nset i 0\nload xs i xs\n",
+            OptLevel::O2,
+        )
+        .unwrap_err()
+        .downcast::<Diagnostics>()
+        .unwrap();
+        let lines: Vec<_> = error.0.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [1, 1, 3, 4, 5, 7]);
+    }
+
+    #[test]
+    fn sanitized_blocks() {
+        // Loops removed by the optimizer must not leave temporary cell checks behind
+        let source = r#"
+set a 10
+match a 10 90
+    prints "no"
+case
+    prints "Z"
+case
+    prints "nl"
+end
+set b 3
+if_eq a b
+    prints "no"
+else
+    prints " ok"
+end
+if_lt b a
+    prints " lt"
+end
+set i 5
+while_nz i
+    dec i
+    if_eq i 2
+        break
+    end
+    printc i
+end
+"#;
+        for level in [
+            OptLevel::O0,
+            OptLevel::O1,
+            OptLevel::O2,
+            OptLevel::O3,
+            OptLevel::Os,
+        ] {
+            let options = Options {
+                sanitize: true,
+                ..Default::default()
+            };
+            let code = crate::pipeline::compile_with(source, level, options)
+                .unwrap()
+                .code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "nl ok lt43",
+                "{level:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn wide_variables() {
+        // Carries and borrows across cells, mixed with one cell variables
+        let source = r#"
+var16 a
+var32 b
+var32 q
+var16 r
+set a 0
+dec a
+printc a
+prints " "
+inc a
+inc_by a 300
+dec_by a 45
+printc a
+prints " "
+set b -2
+inc_by b 0x10002
+printc b
+prints " "
+set a 60000
+set b 123456789
+div b a r q
+printc q
+prints " "
+printc r
+prints " "
+set x 200
+add a x
+sub a r
+printc a 6 '0'
+prints " "
+set b 300
+mul b a
+printx b
+prints " "
+printb r
+prints " "
+compare a x c
+printc c
+prints " "
+if_gt b 99999
+    if_eq a 23411
+        prints "eq "
+    end
+    if_neq a 23411
+        prints "neq "
+    end
+end
+set a 258
+while_ge a x
+    dec_by a 20
+    if_lt a 230
+        break
+    end
+end
+printc a
+prints " "
+readn b
+printc b
+"#;
+        for (level, sanitize) in [
+            (OptLevel::O0, false),
+            (OptLevel::O2, false),
+            (OptLevel::O3, true),
+        ] {
+            let options = Options {
+                sanitize,
+                ..Default::default()
+            };
+            let code = crate::pipeline::compile_with(source, level, options)
+                .unwrap()
+                .code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "4000000000".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "65535 255 65536 2057 36789 023411 006b2ac4 1000111110110101 2 eq 218 4000000000",
+                "{level:?}"
+            );
+        }
+
+        // Constants are out of range for the width of the variable they are used with
+        let error = crate::pipeline::compile(
+            "var16 a\nset a 65536\nset a -65535\nset b 256\nvar32 c\nset c -4294967295\n",
+            OptLevel::O2,
+        )
+        .unwrap_err()
+        .downcast::<Diagnostics>()
+        .unwrap();
+        let lines: Vec<_> = error.0.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [2, 4]);
+
+        let error = crate::pipeline::compile(
+            "var16 a\nvar16 a\nset b 1\npush a\narray xs 2\nload xs a b\nvar32 b\n",
+            OptLevel::O2,
+        )
+        .unwrap_err()
+        .downcast::<Diagnostics>()
+        .unwrap();
+        let lines: Vec<_> = error.0.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [2, 4, 6, 7]);
+    }
+
+    #[test]
+    fn signed_variables() {
+        // Division truncates toward zero, ordering and printing use two's complement
+        let source = r#"
+svar a
+svar b
+svar r
+svar q
+set a -7
+set b 2
+div a b r q
+printc q
+prints " "
+printc r
+prints " "
+set b -2
+div a b r q
+printc q
+prints " "
+printc r
+prints " "
+set a 7
+div a b r q
+printc q
+prints " "
+printc r
+prints " "
+set a -128
+printc a
+prints "|"
+set a -5
+printc a 4
+prints "|"
+printc a 4 '0'
+prints "|"
+set a 42
+printc a 4
+prints "|"
+set a -50
+printc a 5 '*'
+prints "|"
+compare a b c
+printc c
+prints " "
+if_lt a 0
+  prints "neg "
+end
+if_gt a -51
+  prints "gt "
+end
+if_lt a b
+  prints "lt "
+end
+set a -3
+while_lt a 2
+  printc a
+  inc a
+end
+prints " "
+var16 w
+cast a w
+printc w
+prints " "
+set a 0
+dec a
+cast a u
+printc u
+"#;
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let code = crate::pipeline::compile(source, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "-3 -1 3 -1 -3 1 -128|  -5|-005|  42|**-50|1 neg gt lt -3-2-101 2 255",
+                "{level:?}"
+            );
+        }
+
+        // Sign extension into wide variables
+        let source = "svar a\nvar16 w\nset a -2\ncast a w\nprintc w\n";
+        let code = crate::pipeline::compile(source, OptLevel::O2).unwrap().code;
+        let mut output = Vec::new();
+        Interpreter::new()
+            .run(&code, &mut "".as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(output, b"65534");
+
+        let error = crate::pipeline::compile("svar a\nset a 128\n", OptLevel::O2)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("between -128 and 127"), "{error}");
+
+        let error = crate::pipeline::compile(
+            "svar a\nset b 1\nadd a b\nif_lt b a\nend\ncast a b\nsvar b\ndiv a a a b\n",
+            OptLevel::O2,
+        )
+        .unwrap_err()
+        .downcast::<Diagnostics>()
+        .unwrap();
+        let lines: Vec<_> = error.0.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [3, 4, 7, 8]);
+    }
+
+    #[test]
+    fn bitwise() {
+        let source = r#"
+set a 0b11001010
+set b 0b10101100
+copy a c
+and c b
+printb c
+prints " "
+copy a c
+or c b
+printb c
+prints " "
+copy a c
+xor c b
+printb c
+prints " "
+copy a c
+not c
+printb c
+prints " "
+copy a c
+shl c 3
+printb c
+prints " "
+copy a c
+shr c 3
+printb c
+prints " "
+copy a c
+shr c 8
+printc c
+prints " "
+and a a
+printc a
+prints " "
+svar s
+set s -8
+shr s 1
+printc s
+"#;
+        for level in [OptLevel::O0, OptLevel::O2, OptLevel::O3] {
+            let code = crate::pipeline::compile(source, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "10001000 11101110 01100110 00110101 01010000 00011001 0 202 124",
+                "{level:?}"
+            );
+        }
+
+        let error = crate::pipeline::compile("var16 w\nset b 1\nxor b w\nshl w 1\n", OptLevel::O2)
+            .unwrap_err()
+            .downcast::<Diagnostics>()
+            .unwrap();
+        let lines: Vec<_> = error.0.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [3, 4]);
+    }
+
+    #[test]
+    fn booleans() {
+        let source = r#"
+set a 1
+set b 0
+copy a c
+land c b
+printc c
+copy a c
+lor c b
+printc c
+copy b c
+lor c b
+printc c
+copy a c
+land c a
+printc c
+copy a c
+lnot c
+printc c
+lnot c
+printc c
+set x 42
+bool x
+printc x
+set x 0
+bool x
+printc x
+prints " "
+set x 7
+if_nz x
+    printc x
+else
+    prints "no"
+end
+if_z x
+    prints "no"
+else
+    prints "z"
+end
+set x 0
+if_z x
+    prints "y"
+    if_nz x
+        prints "no"
+    end
+end
+var16 w
+set w 256
+if_nz w
+    prints "w"
+end
+if_z w
+    prints "no"
+end
+set i 3
+while_nz i
+    dec i
+    if_z i
+        break
+    end
+    printc i
+end
+"#;
+        for level in [OptLevel::O0, OptLevel::O2, OptLevel::O3] {
+            let code = crate::pipeline::compile(source, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "01010110 7zyw21",
+                "{level:?}"
+            );
+        }
+
+        let error = crate::pipeline::compile("var16 w\nset b 1\nland b w\nlnot w\n", OptLevel::O2)
+            .unwrap_err()
+            .downcast::<Diagnostics>()
+            .unwrap();
+        let lines: Vec<_> = error.0.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [3, 4]);
+    }
+
+    #[test]
+    fn loop_jumps() {
+        let source = r#"
+set i 0
+while_lt i 10
+    inc i
+    if_eq i 3
+        continue
+    end
+    if_eq i 6
+        break
+    else
+        printc i
+    end
+    prints "."
+end
+prints " "
+set i 0
+set three 3
+until_eq i three
+    inc i
+    set j 0
+    while_nz i
+        inc j
+        if_eq j 2
+            continue 2
+        end
+        if_eq i 3
+            if_eq j 1
+                break 2
+            end
+        end
+        printc i
+        printc j
+    end
+    prints "bad"
+end
+prints " "
+set k 3
+while_nz k
+    dec k
+    match k 2
+        prints "bad"
+    case
+        break
+    end
+    prints "bad"
+end
+printc k
+prints " "
+set i 0
+set n 6
+while_lt i n
+    inc i
+    match i 2 4
+        prints "d"
+    case
+        prints "four"
+        if_eq i n
+            prints "bad"
+        else
+            break
+        end
+    case
+        prints "two"
+        continue
+    end
+    printc i
+end
+set j 0
+until_eq j i
+    inc j
+end
+printc j
+prints " "
+set x 5
+match x 5
+    prints "d"
+case
+    prints "d"
+    inc x
+end
+printc x
+"#;
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let code = crate::pipeline::compile(source, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                "1.2.4.5. 1121 2 d1twod3four4 d6",
+                "{level:?}"
+            );
+        }
+
+        let error = crate::pipeline::compile(
+            "set a 1\nbreak\nwhile_nz a\ncontinue 2\nbreak 0\nif_eq a 1\nbreak 1\nend\nend\n",
+            OptLevel::O2,
+        )
+        .unwrap_err()
+        .downcast::<Diagnostics>()
+        .unwrap();
+        let lines: Vec<_> = error.0.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [2, 4, 5]);
+    }
+
     #[test]
     fn parse_examples() {
         // Note: This only parses the examples and codegens them, it does not run them.
@@ -326,6 +1389,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn source_map() {
+        let code = std::fs::read_to_string("examples/lir/fizzbuzz.lir").unwrap();
+        let (parsed, spans) = crate::lir::parser::parse_with_spans(&code).unwrap();
+        let (bf, ranges) = Codegen::new(parsed).codegen_with_map().unwrap();
+
+        // Every instruction is followed by a separator
+        let joined = ranges
+            .iter()
+            .map(|r| format!("{}#", &bf[r.clone()]))
+            .collect::<String>();
+        assert_eq!(joined, bf);
+
+        let (optimized, optimized_ranges) =
+            bf::optimize_with_map(bf.clone(), &ranges, OptLevel::O2, false);
+        let joined = optimized_ranges
+            .iter()
+            .map(|r| &optimized[r.clone()])
+            .collect::<String>();
+        assert_eq!(joined, optimized);
+
+        let map = SourceMap::new(&code, &spans, ranges, Some(optimized_ranges));
+        let entry = &map.entries[5];
+        assert_eq!((entry.line, entry.column), (7, 1));
+        assert_eq!(entry.source, "until_eq n zero");
+        assert_eq!(map.lookup(entry.output().start), Some(entry));
+        assert_eq!(map.lookup(optimized.len()), None);
+
+        let div = map.entries.iter().find(|e| e.line == 8).unwrap();
+        assert_eq!(div.source, "div n three r q");
+        assert_eq!(map.lookup(div.output().end - 1), Some(div));
+        assert!(map
+            .to_json("fizzbuzz.lir")
+            .contains(r#""line": 8, "column": 5, "source": "div n three r q""#));
+    }
+
     #[test]
     fn run_fibonacci_sequence() {
         let code = "set f_n-1 1\n
@@ -354,7 +1453,7 @@ end";
             let mut stdin = "".as_bytes();
             let mut stdout = Box::new(Vec::new());
             let interpret = bf::interpreter::Interpreter::new();
-            interpret.run(&code, &mut stdin, &mut stdout);
+            interpret.run(&code, &mut stdin, &mut stdout).unwrap();
 
             assert_eq!(
                 stdout.as_slice(),
@@ -402,7 +1501,7 @@ prints '\\n'";
             let mut stdin = "".as_bytes();
             let mut stdout = Box::new(Vec::new());
             let interpret = bf::interpreter::Interpreter::new();
-            interpret.run(&code, &mut stdin, &mut stdout);
+            interpret.run(&code, &mut stdin, &mut stdout).unwrap();
 
             assert_eq!(
                 String::from_utf8(*stdout.clone()).unwrap(),
@@ -475,7 +1574,7 @@ end";
                     .as_bytes();
             let mut stdout = Box::new(Vec::new());
             let interpret = bf::interpreter::Interpreter::new();
-            interpret.run(&code, &mut stdin, &mut stdout);
+            interpret.run(&code, &mut stdin, &mut stdout).unwrap();
 
             assert_eq!(
                 String::from_utf8(*stdout.clone()).unwrap(),
@@ -493,7 +1592,7 @@ end";
             .read_to_string(&mut read_code)
             .unwrap();
 
-        let parsed = crate::lir::parser::parse(&*read_code).expect("Failed to parse LIR");
+        let parsed = crate::lir::parser::parse(&read_code).expect("Failed to parse LIR");
         let bf = Codegen::new_test(parsed)
             .codegen()
             .expect("Failed to generate BF");
@@ -504,7 +1603,7 @@ end";
             let mut stdin = "Hello World!".as_bytes();
             let mut stdout = Box::new(Vec::new());
             let interpret = bf::interpreter::Interpreter::new();
-            interpret.run(&code, &mut stdin, &mut stdout);
+            interpret.run(&code, &mut stdin, &mut stdout).unwrap();
 
             assert_eq!(String::from_utf8(*stdout.clone()).unwrap(), "!dlroW olleH");
         }
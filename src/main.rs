@@ -1,10 +1,15 @@
 use anyhow::Result;
 use argh::FromArgs;
-use std::io::{stdin, Read};
+use bf::interpreter::{CellWidth, EofPolicy, InterpreterConfig, OverflowPolicy, PointerPolicy};
+use pipeline::OptLevel;
+use std::io::{stdin, Read, Write};
 use std::path::PathBuf;
 
 mod bf;
+mod debugger;
 mod lir;
+mod pipeline;
+mod profiler;
 
 #[derive(FromArgs, Debug)]
 /// Compile and/or interpret Alkoholiq
@@ -13,41 +18,156 @@ struct CliArgs {
     #[argh(switch, short = 'b')]
     brainfuck: bool,
 
-    /// optimize the generated brainfuck
-    // TODO This is quite confusing, make it level based like gcc
-    #[argh(option, short = 'o', default = "true")]
-    optimize: bool,
+    /// optimization level: 0, 1, 2, 3 or s, default 2. Also accepted as `-O2`
+    #[argh(option, short = 'O')]
+    opt_level: Option<OptLevel>,
+
+    /// initial number of cells on the interpreter's tape, default 30000
+    #[argh(option, from_str_fn(parse_tape_length))]
+    tape_length: Option<usize>,
+
+    /// bits per cell: 8, 16 or 32, default 8
+    #[argh(option)]
+    cell_width: Option<CellWidth>,
+
+    /// what happens when a cell goes out of range: wrap or error, default wrap
+    #[argh(option)]
+    overflow: Option<OverflowPolicy>,
+
+    /// what reading past the end of input stores: zero, max (-1) or unchanged, default zero
+    #[argh(option)]
+    eof: Option<EofPolicy>,
+
+    /// what happens when the pointer leaves the tape: wrap, error or grow, default wrap
+    #[argh(option)]
+    pointer: Option<PointerPolicy>,
+
+    /// run the program in an interactive debugger reading commands from stdin
+    #[argh(switch, short = 'd')]
+    debug: bool,
+
+    /// file the program reads its input from, stdin by default or nothing when debugging
+    #[argh(option)]
+    program_input: Option<PathBuf>,
+
+    /// print how many brainfuck ops every LIR instruction executed to stderr
+    #[argh(switch)]
+    profile: bool,
+
+    /// check array indices at runtime, stopping with an error when one is out of range
+    #[argh(switch)]
+    sanitize: bool,
+
+    /// maximum number of brainfuck ops to execute
+    #[argh(option)]
+    step_limit: Option<usize>,
+
+    /// write a JSON source map from brainfuck back to LIR instructions to this file
+    #[argh(option)]
+    emit_sourcemap: Option<PathBuf>,
 
     /// input file
     #[argh(positional)]
     input: Option<PathBuf>,
 }
 
+fn parse_tape_length(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) | Err(_) => Err(format!(
+            "Invalid tape length `{value}`, expected a number of cells above zero"
+        )),
+        Ok(length) => Ok(length),
+    }
+}
+
+/// Parse the command line like [`argh::from_env`], but also accept `-O2` for `-O 2` like gcc
+///
+/// Only whole arguments before `--` are split, so values like `--program-input -Ofoo` are kept.
+fn parse_args() -> CliArgs {
+    let mut options = true;
+    let strings: Vec<String> = std::env::args()
+        .enumerate()
+        .flat_map(|(i, arg)| {
+            options &= arg != "--";
+            match arg.as_str() {
+                "-O0" | "-O1" | "-O2" | "-O3" | "-Os" if i > 0 && options => {
+                    vec!["-O".to_string(), arg[2..].to_string()]
+                }
+                _ => vec![arg],
+            }
+        })
+        .collect();
+    let command = std::path::Path::new(&strings[0])
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(&strings[0]);
+    let strs: Vec<&str> = strings[1..].iter().map(String::as_str).collect();
+
+    CliArgs::from_args(&[command], &strs).unwrap_or_else(|early_exit| {
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun {command} --help for more information.",
+                    early_exit.output
+                );
+                1
+            }
+        })
+    })
+}
+
 fn main() -> Result<()> {
-    let args: CliArgs = argh::from_env();
+    let args = parse_args();
+
+    if args.debug && args.input.as_ref().is_none_or(|p| p.as_os_str() == "-") {
+        anyhow::bail!("The debugger reads commands from stdin, so the program has to be a file");
+    }
 
     let mut input_cursor: Box<dyn Read>;
 
-    if args.input.is_none() {
-        input_cursor = Box::new(stdin());
-    } else {
-        let path = args.input.unwrap();
-        if path.as_os_str() == "-" {
-            input_cursor = Box::new(stdin());
-        } else {
+    match &args.input {
+        Some(path) if path.as_os_str() != "-" => {
             input_cursor = Box::new(std::fs::File::open(path)?);
         }
+        _ => input_cursor = Box::new(stdin()),
     }
     let mut input = String::new();
     input_cursor.read_to_string(&mut input)?;
 
-    let parsed = lir::parser::parse(&input)?;
+    let default = InterpreterConfig::default();
+    let eof = args.eof.unwrap_or(default.eof);
+    let level = args.opt_level.unwrap_or_default();
+    let options = pipeline::Options {
+        eof,
+        sanitize: args.sanitize,
+    };
+    let compiled = match pipeline::compile_with(&input, level, options) {
+        Ok(compiled) => compiled,
+        Err(e) => match e.downcast_ref::<lir::diagnostic::Diagnostics>() {
+            Some(diagnostics) => {
+                let file = match &args.input {
+                    Some(path) if path.as_os_str() != "-" => path.to_string_lossy(),
+                    _ => "<stdin>".into(),
+                };
+                eprintln!("{}", diagnostics.render(&file, &input));
+                std::process::exit(1);
+            }
+            None => return Err(e),
+        },
+    };
+    let code = compiled.code;
+    let source_map = compiled.source_map;
 
-    let codegen = lir::codegen::Codegen::new(parsed);
-    let mut code = codegen.codegen()?;
-
-    if args.optimize {
-        code = bf::optimize(code);
+    if let Some(path) = &args.emit_sourcemap {
+        let file = match &args.input {
+            Some(input) => input.to_string_lossy(),
+            None => "-".into(),
+        };
+        std::fs::write(path, source_map.to_json(&file))?;
     }
 
     if args.brainfuck {
@@ -56,8 +176,51 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let interpreter = bf::interpreter::Interpreter::new();
-    interpreter.run(&code, &mut stdin(), &mut std::io::stdout());
+    let config = InterpreterConfig {
+        tape_length: args.tape_length.unwrap_or(default.tape_length),
+        cell_width: args.cell_width.unwrap_or(default.cell_width),
+        overflow: args.overflow.unwrap_or(default.overflow),
+        eof,
+        pointer: args.pointer.unwrap_or(default.pointer),
+        step_limit: args.step_limit.unwrap_or(default.step_limit),
+    };
+
+    let mut program_input: Box<dyn Read> = match &args.program_input {
+        Some(path) => Box::new(std::fs::File::open(path)?),
+        None if args.debug => Box::new(std::io::empty()),
+        None => Box::new(stdin()),
+    };
+    let mut stdout = std::io::stdout();
+
+    if args.debug {
+        let mut debugger = debugger::Debugger::new(&code, config, source_map, compiled.analysis)?;
+        return debugger.run(stdin().lock(), &mut program_input, &mut stdout);
+    }
+
+    let mut interpreter = bf::interpreter::Interpreter::with_config(config);
+    if args.profile {
+        interpreter = interpreter.with_profiling();
+    }
+
+    match interpreter.run(&code, &mut program_input, &mut stdout) {
+        Ok(report) => {
+            if let Some(op_counts) = report.profile {
+                stdout.flush()?;
+                let profile = profiler::Profile::new(&source_map, &op_counts);
+                eprintln!();
+                eprintln!("{profile}");
+            }
+        }
+        Err(e) => {
+            stdout.flush()?;
+            eprintln!();
+            eprintln!("Runtime error: {e}");
+            if let Some(entry) = source_map.preceding(e.position()) {
+                eprintln!("  at line {}: `{}`", entry.line, entry.source);
+            }
+            std::process::exit(1);
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,141 @@
+use crate::bf;
+use crate::bf::interpreter::EofPolicy;
+use crate::lir;
+use crate::lir::analysis::InstructionsAnalysis;
+use crate::lir::codegen::Codegen;
+use crate::lir::diagnostic;
+use crate::lir::sourcemap::SourceMap;
+use anyhow::Result;
+use std::str::FromStr;
+
+mod tests;
+
+/// How hard to optimize, like gcc's `-O`
+///
+/// - `O0` emits exactly what codegen generated, including the `#` separators, so the
+///   interpreter checks that temporary variables are zero after every instruction
+/// - `O1` folds runs of constant changes to the same variable in LIR, and cancels pointer
+///   moves and recognizes clear and multiplication loops in brainfuck
+/// - `O2` also tracks known cell values to remove clears and loops that never run
+/// - `O3` also uses known values to make code faster even if it gets longer
+/// - `Os` is `O2`, but never makes code longer to make it faster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    O0,
+    O1,
+    #[default]
+    O2,
+    O3,
+    Os,
+}
+
+impl OptLevel {
+    /// Whether known cell values are tracked in brainfuck
+    pub fn known_values(self) -> bool {
+        !matches!(self, OptLevel::O0 | OptLevel::O1)
+    }
+
+    /// Whether code `old` bytes long should be replaced by faster code `new` bytes long
+    pub fn accept_faster(self, old: usize, new: usize) -> bool {
+        match self {
+            OptLevel::O3 => true,
+            OptLevel::Os => new < old,
+            _ => new <= old,
+        }
+    }
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            "s" => Ok(OptLevel::Os),
+            _ => Err(format!(
+                "Invalid optimization level `{s}`, expected 0, 1, 2, 3 or s"
+            )),
+        }
+    }
+}
+
+/// What the code is generated for, besides the optimization level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    /// What `,` does at the end of input in the interpreter the code runs in
+    pub eof: EofPolicy,
+    /// Check array indices at runtime, see [`Codegen::with_sanitize`]
+    ///
+    /// The checks stop the program at the `#` after the instruction, so optimized code keeps
+    /// them too.
+    pub sanitize: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            eof: EofPolicy::Zero,
+            sanitize: false,
+        }
+    }
+}
+
+/// A compiled program
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    /// Brainfuck to run, with a header unless compiled with [`OptLevel::O0`]
+    pub code: String,
+    pub analysis: InstructionsAnalysis,
+    pub source_map: SourceMap,
+}
+
+/// Compile LIR source into brainfuck with the default [`Options`]
+///
+/// Errors in the source are returned as [`diagnostic::Diagnostics`].
+#[allow(dead_code)]
+pub fn compile(source: &str, level: OptLevel) -> Result<Compiled> {
+    compile_with(source, level, Options::default())
+}
+
+/// Compile LIR source into brainfuck with `options`
+pub fn compile_with(source: &str, level: OptLevel, options: Options) -> Result<Compiled> {
+    let (mut instructions, spans) = lir::parser::parse_with_spans(source)?;
+    if level != OptLevel::O0 {
+        instructions = lir::optim::fold_constants(instructions);
+    }
+
+    let analysis = InstructionsAnalysis::new(instructions.clone())
+        .map_err(|e| diagnostic::locate(e, source, &spans))?;
+    let (code, ranges) = Codegen::new(instructions)
+        .with_opt_level(level)
+        .with_eof(options.eof)
+        .with_sanitize(options.sanitize)
+        .codegen_with_map()
+        .map_err(|e| diagnostic::locate(e, source, &spans))?;
+
+    if level == OptLevel::O0 {
+        let source_map = SourceMap::new(source, &spans, ranges, None);
+        return Ok(Compiled {
+            code,
+            analysis,
+            source_map,
+        });
+    }
+
+    let (code, optimized) = bf::optimize_with_map(code, &ranges, level, options.sanitize);
+    let header = bf::add_header(String::new()).len();
+    let optimized = optimized
+        .into_iter()
+        .map(|r| r.start + header..r.end + header)
+        .collect();
+    let source_map = SourceMap::new(source, &spans, ranges, Some(optimized));
+
+    Ok(Compiled {
+        code: bf::add_header(code),
+        analysis,
+        source_map,
+    })
+}
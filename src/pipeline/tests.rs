@@ -0,0 +1,122 @@
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod tests {
+    use crate::bf;
+    use crate::bf::interpreter::{CellWidth, EofPolicy, Interpreter, InterpreterConfig};
+    use crate::pipeline::{compile, compile_with, OptLevel, Options};
+
+    const LEVELS: [OptLevel; 5] = [
+        OptLevel::O0,
+        OptLevel::O1,
+        OptLevel::O2,
+        OptLevel::O3,
+        OptLevel::Os,
+    ];
+
+    #[test]
+    fn levels() {
+        assert_eq!("s".parse(), Ok(OptLevel::Os));
+        assert_eq!("3".parse(), Ok(OptLevel::O3));
+        assert!("4".parse::<OptLevel>().is_err());
+
+        let code = "set a 5\ninc a\ndec_by a 2\nprint a\n";
+        let o0 = compile(code, OptLevel::O0).unwrap().code;
+        assert_eq!(o0.matches('#').count(), 4);
+        assert!(!o0.starts_with("[Generated"));
+
+        let o1 = compile(code, OptLevel::O1).unwrap();
+        assert!(o1.code.starts_with("[Generated"));
+        assert!(!o1.code.contains('#'));
+        // Folded into the `set`, which clears first as O1 doesn't know the cell is zero
+        assert!(o1.source_map.entries[1].output().is_empty());
+        assert!(o1.source_map.entries[2].output().is_empty());
+        let set = o1.source_map.entries[0].output().clone();
+        assert_eq!(&o1.code[set], "[-]++++");
+
+        let o2 = compile(code, OptLevel::O2).unwrap();
+        let set = o2.source_map.entries[0].output().clone();
+        assert_eq!(&o2.code[set], "++++");
+    }
+
+    #[test]
+    fn examples_agree() {
+        for name in [
+            "comp_ascii",
+            "fib",
+            "fizzbuzz",
+            "rot13",
+            "sort",
+            "string_reverse",
+        ] {
+            let source = std::fs::read_to_string(format!("examples/lir/{name}.lir")).unwrap();
+            let mut outputs = Vec::new();
+            let mut sizes = Vec::new();
+
+            for level in LEVELS {
+                let code = compile(&source, level).unwrap().code;
+                let mut output = Vec::new();
+                Interpreter::new()
+                    .run(&code, &mut "Hello\n".as_bytes(), &mut output)
+                    .unwrap();
+                outputs.push(output);
+                let header = bf::add_header(String::new());
+                let body = code.strip_prefix(&header).unwrap_or(&code);
+                sizes.push(body.chars().filter(|&c| c != '#').count());
+            }
+
+            assert!(outputs.iter().all(|o| *o == outputs[0]), "{name}");
+            // O1, O2 and Os only ever shrink code
+            assert!(sizes[1] < sizes[0], "{name}");
+            assert!(sizes[2] <= sizes[1], "{name}");
+            assert!(sizes[4] <= sizes[2], "{name}");
+        }
+    }
+
+    #[test]
+    fn match_agrees() {
+        // A case that never runs must not hide that the default case does
+        let code = "set a 10\nmatch a 10 90\nprints \"no\"\ncase\nprints \"Z\"\ncase\nprints \"nl\"\nend\n";
+        for level in LEVELS {
+            let code = compile(code, level).unwrap().code;
+            let mut output = Vec::new();
+            Interpreter::new()
+                .run(&code, &mut "".as_bytes(), &mut output)
+                .unwrap();
+            assert_eq!(output, b"nl", "{level:?}");
+        }
+    }
+
+    #[test]
+    fn read_eof() {
+        let code =
+            "set done 1\nread c eof\nuntil_eq eof done\nprint c\nread c eof\nend\nprints \"!\"\n";
+        let input = b"a\0\xffb";
+
+        for eof in [EofPolicy::Zero, EofPolicy::Max, EofPolicy::Unchanged] {
+            let options = Options {
+                eof,
+                ..Default::default()
+            };
+            let code = compile_with(code, OptLevel::O2, options).unwrap().code;
+            for cell_width in [CellWidth::U8, CellWidth::U16] {
+                let config = InterpreterConfig {
+                    eof,
+                    cell_width,
+                    ..Default::default()
+                };
+                let mut output = Vec::new();
+                Interpreter::with_config(config)
+                    .run(&code, &mut &input[..], &mut output)
+                    .unwrap();
+
+                // Only cells wider than the bytes read can tell every byte from the end of input
+                let expected: &[u8] = match (eof, cell_width) {
+                    (EofPolicy::Zero, _) => b"a!",
+                    (_, CellWidth::U8) => b"a\0!",
+                    _ => b"a\0\xffb!",
+                };
+                assert_eq!(output, expected, "{eof:?} {cell_width:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::lir::sourcemap::SourceMap;
+use std::fmt::{Display, Formatter};
+
+mod tests;
+
+/// Longest instruction text shown before it is cut off
+const MAX_SOURCE_WIDTH: usize = 40;
+
+/// Executed ops of a single LIR instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileRow {
+    /// Line of the instruction, `None` for code outside of any instruction
+    pub line: Option<usize>,
+    pub source: String,
+    pub ops: usize,
+}
+
+/// Cost of every LIR instruction of a run, most expensive first
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Profile {
+    pub rows: Vec<ProfileRow>,
+    pub total: usize,
+}
+
+impl Profile {
+    /// Attribute the op counts of [`crate::bf::interpreter::ExecutionReport::profile`] to the
+    /// instructions that generated the ops
+    pub fn new(source_map: &SourceMap, op_counts: &[(usize, usize)]) -> Self {
+        let mut per_entry = vec![0; source_map.entries.len()];
+        let mut outside = 0;
+
+        for &(position, count) in op_counts {
+            match source_map.lookup_index(position) {
+                Some(entry) => per_entry[entry] += count,
+                None => outside += count,
+            }
+        }
+
+        let mut rows: Vec<ProfileRow> = source_map
+            .entries
+            .iter()
+            .zip(per_entry)
+            .filter(|(_, ops)| *ops > 0)
+            .map(|(entry, ops)| ProfileRow {
+                line: Some(entry.line),
+                source: entry.source.clone(),
+                ops,
+            })
+            .collect();
+
+        // Headers, separators and raw code that is not part of an instruction
+        if outside > 0 {
+            rows.push(ProfileRow {
+                line: None,
+                source: "(outside of instructions)".to_string(),
+                ops: outside,
+            });
+        }
+
+        rows.sort_by(|a, b| b.ops.cmp(&a.ops).then(a.line.cmp(&b.line)));
+
+        Self {
+            total: rows.iter().map(|r| r.ops).sum(),
+            rows,
+        }
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:>6}  {:<width$}  {:>14}  {:>6}",
+            "line",
+            "instruction",
+            "ops executed",
+            "share",
+            width = MAX_SOURCE_WIDTH
+        )?;
+
+        for row in &self.rows {
+            let line = row.line.map_or("-".to_string(), |l| l.to_string());
+            let source = if row.source.chars().count() > MAX_SOURCE_WIDTH {
+                let cut: String = row.source.chars().take(MAX_SOURCE_WIDTH - 3).collect();
+                format!("{cut}...")
+            } else {
+                row.source.clone()
+            };
+            let share = row.ops as f64 / self.total.max(1) as f64 * 100.0;
+
+            writeln!(
+                f,
+                "{line:>6}  {source:<width$}  {:>14}  {share:>5.1}%",
+                row.ops,
+                width = MAX_SOURCE_WIDTH
+            )?;
+        }
+
+        write!(
+            f,
+            "{:>6}  {:<width$}  {:>14}  {:>5.1}%",
+            "",
+            "total",
+            self.total,
+            100.0,
+            width = MAX_SOURCE_WIDTH
+        )
+    }
+}
@@ -0,0 +1,46 @@
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod tests {
+    use crate::bf::interpreter::Interpreter;
+    use crate::pipeline::{compile, OptLevel};
+    use crate::profiler::{Profile, ProfileRow};
+
+    #[test]
+    fn profile() {
+        let code = "set n 10
+while_nz n
+    printc n
+    dec n
+end
+";
+        let compiled = compile(code, OptLevel::O2).unwrap();
+        let source_map = compiled.source_map;
+
+        let report = Interpreter::new()
+            .with_profiling()
+            .run(&compiled.code, &mut "".as_bytes(), &mut Vec::new())
+            .unwrap();
+        let profile = Profile::new(&source_map, &report.profile.unwrap());
+
+        assert_eq!(profile.total, report.steps);
+        assert_eq!(profile.rows[0].source, "printc n");
+        assert_eq!(profile.rows[0].line, Some(3));
+        assert!(profile.rows[0].ops * 10 > profile.total * 9);
+
+        // Every instruction ran, `dec n` moves to `n` and decrements it every iteration
+        assert_eq!(profile.rows.iter().filter(|r| r.line.is_some()).count(), 5);
+        assert!(profile.rows.contains(&ProfileRow {
+            line: Some(4),
+            source: "dec n".to_string(),
+            ops: 20
+        }));
+
+        let table = profile.to_string();
+        assert!(table
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("     3  printc n"));
+        assert!(table.ends_with("100.0%"));
+    }
+}